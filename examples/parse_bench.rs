@@ -0,0 +1,54 @@
+//! Times `parse_kv2` against `parse_kv2_borrowed` on a generated document,
+//! run with `cargo run --release --example parse_bench`
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use kv2::{parse_kv2, parse_kv2_borrowed};
+
+fn input(operators: usize) -> String {
+    let mut input = String::from(
+        "\"DmeParticleSystemDefinition\"\n{\n\"id\" \"elementid\" \"3535d7f5-7d31-4b97-b772-46fadd300992\"\n\"operators\" \"element_array\"\n[\n",
+    );
+    for i in 0..operators {
+        if i > 0 {
+            input.push_str(",\n");
+        }
+        write!(
+            input,
+            "\"DmeParticleOperator\"\n{{\n\"id\" \"elementid\" \"00000000-0000-4000-8000-{:012}\"\n\"functionName\" \"string\" \"alpha_fade\"\n\"start_alpha\" \"float\" \"1\"\n\"end_alpha\" \"float\" \"0\"\n\"color\" \"color\" \"255 255 255 255\"\n\"values\" \"float_array\" [ \"0\", \"0.5\", \"1\" ]\n}}",
+            i
+        )
+        .unwrap();
+    }
+    input.push_str("\n]\n}\n");
+    input
+}
+
+fn time(runs: u32, mut parse: impl FnMut()) -> Duration {
+    parse();
+    let start = Instant::now();
+    for _ in 0..runs {
+        parse();
+    }
+    start.elapsed() / runs
+}
+
+fn main() {
+    let input = input(20_000);
+    let runs = 10;
+    println!("{} KiB, {} runs each", input.len() / 1024, runs);
+
+    let borrowed = time(runs, || {
+        parse_kv2_borrowed(&input).unwrap();
+    });
+    let owned = time(runs, || {
+        parse_kv2(&input).unwrap();
+    });
+    println!("parse_kv2_borrowed {:>8.2?}", borrowed);
+    println!("parse_kv2          {:>8.2?}", owned);
+    // What owning the strings costs on top of parsing
+    println!(
+        "difference         {:>8.2?}",
+        owned.saturating_sub(borrowed)
+    );
+}
@@ -0,0 +1,185 @@
+//! Values borrowing their strings from the parsed text
+//!
+//! Keys, class names and string values point into the input unless they
+//! had escapes to resolve, so only the maps, arrays and vectors holding
+//! the values allocate.
+//!
+//! # Example
+//! ```rust
+//! use std::borrow::Cow;
+//! use kv2::{parse_kv2_borrowed, KV2Value, KV2ValueRef};
+//!
+//! let input = r#"
+//! "DmElement"
+//! {
+//! "name" "string" "root"
+//! }
+//! "#;
+//!
+//! let (_, objects) = parse_kv2_borrowed(input).unwrap();
+//! let name = &objects[0].fields["name"];
+//! assert!(matches!(name, KV2ValueRef::String(Cow::Borrowed("root"))));
+//! assert_eq!(name.clone().into_owned(), KV2Value::String("root".to_string()));
+//! ```
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::{KV2Object, KV2Value};
+
+/// A [`KV2Value`] borrowing its strings from the input where it can
+#[derive(Debug, Clone, PartialEq)]
+pub enum KV2ValueRef<'a> {
+    Bool(bool),
+    Int(i64),
+    Double(f64),
+    Vector(Vec<f64>),
+    Quaternion(Vec<f64>),
+    String(Cow<'a, str>),
+    /// Reference to another element by id, empty for a null reference
+    Element(Cow<'a, str>),
+    Array(Vec<KV2ValueRef<'a>>),
+    Object(KV2ObjectRef<'a>),
+}
+
+/// A [`KV2Object`] borrowing its strings from the input where it can
+#[derive(Debug, Clone, PartialEq)]
+pub struct KV2ObjectRef<'a> {
+    pub class_name: Cow<'a, str>,
+    pub fields: HashMap<Cow<'a, str>, KV2ValueRef<'a>>,
+    /// Later values of repeated attributes, see [`KV2Object::repeated`]
+    pub repeated: Vec<(Cow<'a, str>, KV2ValueRef<'a>)>,
+}
+
+impl KV2ValueRef<'_> {
+    /// Copies the borrowed strings, strings that are already owned are
+    /// moved
+    pub fn into_owned(self) -> KV2Value {
+        match self {
+            KV2ValueRef::Bool(b) => KV2Value::Bool(b),
+            KV2ValueRef::Int(i) => KV2Value::Int(i),
+            KV2ValueRef::Double(d) => KV2Value::Double(d),
+            KV2ValueRef::Vector(v) => KV2Value::Vector(v),
+            KV2ValueRef::Quaternion(q) => KV2Value::Quaternion(q),
+            KV2ValueRef::String(s) => KV2Value::String(s.into_owned()),
+            KV2ValueRef::Element(id) => KV2Value::Element(id.into_owned()),
+            KV2ValueRef::Array(items) => {
+                KV2Value::Array(items.into_iter().map(KV2ValueRef::into_owned).collect())
+            }
+            KV2ValueRef::Object(object) => KV2Value::Object(object.into_owned()),
+        }
+    }
+}
+
+impl KV2ObjectRef<'_> {
+    /// Copies the borrowed strings, strings that are already owned are
+    /// moved
+    pub fn into_owned(self) -> KV2Object {
+        KV2Object {
+            class_name: self.class_name.into_owned(),
+            fields: self
+                .fields
+                .into_iter()
+                .map(|(key, value)| (key.into_owned(), value.into_owned()))
+                .collect(),
+            repeated: self
+                .repeated
+                .into_iter()
+                .map(|(key, value)| (key.into_owned(), value.into_owned()))
+                .collect(),
+        }
+    }
+}
+
+/// What the parser builds, so owned values don't go through a borrowed
+/// tree first
+pub(crate) trait Tree<'a> {
+    type Key: Hash + Eq + Clone + AsRef<str> + std::borrow::Borrow<str>;
+    type Value;
+    type Object;
+
+    fn key(key: Cow<'a, str>) -> Self::Key;
+    /// Converts a value parsed without nested arrays or elements
+    fn value(value: KV2ValueRef<'a>) -> Self::Value;
+    fn array(items: Vec<Self::Value>) -> Self::Value;
+    fn object(
+        class_name: Cow<'a, str>,
+        fields: HashMap<Self::Key, Self::Value>,
+        repeated: Vec<(Self::Key, Self::Value)>,
+    ) -> Self::Object;
+    fn object_value(object: Self::Object) -> Self::Value;
+}
+
+/// Builds [`KV2ObjectRef`]s
+pub(crate) struct Borrowed;
+
+/// Builds [`KV2Object`]s
+pub(crate) struct Owned;
+
+impl<'a> Tree<'a> for Borrowed {
+    type Key = Cow<'a, str>;
+    type Value = KV2ValueRef<'a>;
+    type Object = KV2ObjectRef<'a>;
+
+    fn key(key: Cow<'a, str>) -> Cow<'a, str> {
+        key
+    }
+
+    fn value(value: KV2ValueRef<'a>) -> KV2ValueRef<'a> {
+        value
+    }
+
+    fn array(items: Vec<KV2ValueRef<'a>>) -> KV2ValueRef<'a> {
+        KV2ValueRef::Array(items)
+    }
+
+    fn object(
+        class_name: Cow<'a, str>,
+        fields: HashMap<Cow<'a, str>, KV2ValueRef<'a>>,
+        repeated: Vec<(Cow<'a, str>, KV2ValueRef<'a>)>,
+    ) -> KV2ObjectRef<'a> {
+        KV2ObjectRef {
+            class_name,
+            fields,
+            repeated,
+        }
+    }
+
+    fn object_value(object: KV2ObjectRef<'a>) -> KV2ValueRef<'a> {
+        KV2ValueRef::Object(object)
+    }
+}
+
+impl<'a> Tree<'a> for Owned {
+    type Key = String;
+    type Value = KV2Value;
+    type Object = KV2Object;
+
+    fn key(key: Cow<'a, str>) -> String {
+        key.into_owned()
+    }
+
+    fn value(value: KV2ValueRef<'a>) -> KV2Value {
+        value.into_owned()
+    }
+
+    fn array(items: Vec<KV2Value>) -> KV2Value {
+        KV2Value::Array(items)
+    }
+
+    fn object(
+        class_name: Cow<'a, str>,
+        fields: HashMap<String, KV2Value>,
+        repeated: Vec<(String, KV2Value)>,
+    ) -> KV2Object {
+        KV2Object {
+            class_name: class_name.into_owned(),
+            fields,
+            repeated,
+        }
+    }
+
+    fn object_value(object: KV2Object) -> KV2Value {
+        KV2Value::Object(object)
+    }
+}
@@ -0,0 +1,222 @@
+//! Rendering parse errors and validation issues against the source, in the
+//! style of rustc, or as JSON for tools
+//!
+//! # Example
+//! ```rust
+//! use kv2::{parse_kv2, Diagnostic};
+//!
+//! let input = "\"DmElement\"\n{\n\"name\" \"string\" root\n}\n";
+//! let error = parse_kv2(input).unwrap_err();
+//! let rendered = Diagnostic::from_error(&error, input).render(input, "model.dmx");
+//! assert_eq!(rendered, r#"error: expected a quoted string or "{", found `root`
+//!  --> model.dmx:3:17
+//!   |
+//! 3 | "name" "string" root
+//!   |                 ^^^^ expected a quoted string or "{"
+//!   |
+//!   = note: in [0]/name
+//! "#);
+//! ```
+use std::fmt::{self, Write};
+use std::ops::Range;
+
+use crate::error::{line_column, Kv2Error};
+use crate::validate::ValidationIssue;
+
+/// How tabs are expanded when printing source lines
+const TAB_WIDTH: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A message about a span of the source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// Printed next to the underline
+    pub label: String,
+    /// Byte range of the underlined text
+    pub span: Range<usize>,
+    /// 1-based line of the start of the span
+    pub line: usize,
+    /// 1-based column of the start of the span, in characters
+    pub column: usize,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(
+        severity: Severity,
+        source: &str,
+        span: Range<usize>,
+        message: impl Into<String>,
+        label: impl Into<String>,
+    ) -> Diagnostic {
+        let (line, column) = line_column(source, span.start);
+        Diagnostic {
+            severity,
+            message: message.into(),
+            label: label.into(),
+            span,
+            line,
+            column,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Diagnostic {
+        self.notes.push(note.into());
+        self
+    }
+
+    /// Underlines the token the parser stopped at
+    pub fn from_error(error: &Kv2Error, source: &str) -> Diagnostic {
+        let span = token_at(source, error.offset);
+        let found = match &source[span.clone()] {
+            "" => "end of input".to_string(),
+            token => format!("`{}`", token),
+        };
+
+        let expected = error.describe_expected();
+        let mut diagnostic = Diagnostic::new(
+            Severity::Error,
+            source,
+            span,
+            format!("{}, found {}", expected, found),
+            expected,
+        );
+        if !error.path.is_empty() {
+            diagnostic = diagnostic.with_note(format!("in {}", error.path));
+        }
+        diagnostic
+    }
+
+    /// A warning underlining the token at the issue's offset, `None` if the
+    /// issue wasn't found while parsing and has no offset
+    pub fn from_issue(issue: &ValidationIssue, source: &str) -> Option<Diagnostic> {
+        let span = token_at(source, issue.location.offset?);
+        let mut diagnostic =
+            Diagnostic::new(Severity::Warning, source, span, issue.kind.to_string(), "");
+        if let Some(attribute) = &issue.location.attribute {
+            diagnostic = diagnostic.with_note(format!("attribute {:?}", attribute));
+        }
+        Some(diagnostic)
+    }
+
+    /// Renders the diagnostic with the line it points at and a caret
+    /// underline, `file_name` is shown next to the position
+    pub fn render(&self, source: &str, file_name: &str) -> String {
+        let line_start = source[..self.span.start.min(source.len())]
+            .rfind('\n')
+            .map_or(0, |i| i + 1);
+        let line_end = source[line_start..]
+            .find('\n')
+            .map_or(source.len(), |i| line_start + i);
+        let text = source[line_start..line_end].trim_end_matches('\r');
+
+        let before = &source[line_start..self.span.start.max(line_start).min(line_end)];
+        let underlined = &source[self.span.start.min(line_end)..self.span.end.min(line_end)];
+        let padding = display_width(before);
+        let carets = display_width(underlined).max(1);
+
+        let gutter = self.line.to_string().len();
+        let blank = " ".repeat(gutter);
+
+        let mut out = String::new();
+        let _ = writeln!(out, "{}: {}", self.severity, self.message);
+        let _ = writeln!(
+            out,
+            "{}--> {}:{}:{}",
+            blank, file_name, self.line, self.column
+        );
+        let _ = writeln!(out, "{} |", blank);
+        let _ = writeln!(out, "{} | {}", self.line, expand_tabs(text));
+        let _ = write!(
+            out,
+            "{} | {}{}",
+            blank,
+            " ".repeat(padding),
+            "^".repeat(carets)
+        );
+        if !self.label.is_empty() {
+            let _ = write!(out, " {}", self.label);
+        }
+        out.push('\n');
+        if !self.notes.is_empty() {
+            let _ = writeln!(out, "{} |", blank);
+            for note in &self.notes {
+                let _ = writeln!(out, "{} = note: {}", blank, note);
+            }
+        }
+        out
+    }
+
+    /// The diagnostic as a single JSON object
+    pub fn to_json(&self) -> String {
+        let notes: Vec<String> = self.notes.iter().map(|note| json_string(note)).collect();
+        format!(
+            "{{\"severity\":{},\"message\":{},\"label\":{},\"start\":{},\"end\":{},\"line\":{},\"column\":{},\"notes\":[{}]}}",
+            json_string(&self.severity.to_string()),
+            json_string(&self.message),
+            json_string(&self.label),
+            self.span.start,
+            self.span.end,
+            self.line,
+            self.column,
+            notes.join(",")
+        )
+    }
+}
+
+/// The whitespace-delimited token starting at `offset`, empty at the end
+/// of the input
+fn token_at(source: &str, offset: usize) -> Range<usize> {
+    let start = offset.min(source.len());
+    let len = source[start..]
+        .find(char::is_whitespace)
+        .unwrap_or(source.len() - start);
+    start..start + len
+}
+
+fn expand_tabs(text: &str) -> String {
+    text.replace('\t', &" ".repeat(TAB_WIDTH))
+}
+
+fn display_width(text: &str) -> usize {
+    text.chars()
+        .map(|c| if c == '\t' { TAB_WIDTH } else { 1 })
+        .sum()
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,796 @@
+//! A parsed KV2 document: the header, the root elements and an index of
+//! every element by its `elementid`
+//!
+//! Elements are stored once in an arena and refer to each other through
+//! [`ElementHandle`]s, whether they were nested inline or referenced by id
+//! in the source, so shared elements and cycles need no special handling.
+//!
+//! # Example
+//! ```rust
+//! use kv2::parse_kv2_document;
+//!
+//! let input = r#"
+//! <!-- dmx encoding keyvalues2 1 format dmx 22 -->
+//! "DmElement"
+//! {
+//! "id" "elementid" "df939bf4-8dd6-435c-9eef-a6e25434ecca"
+//! "name" "string" "root"
+//! "exportTags" "DmeExportTags"
+//! {
+//!     "id" "elementid" "9891f8a4-debd-488a-81cd-3d0f02345c74"
+//!     "name" "string" "exportTags"
+//! }
+//! }
+//! "#;
+//!
+//! let (_, doc) = parse_kv2_document(input).unwrap();
+//! assert_eq!(doc.header().unwrap().format, "dmx");
+//! let root = doc[doc.roots()[0]].clone();
+//! let tags = root.child("exportTags").unwrap();
+//! assert_eq!(doc[tags].class_name, "DmeExportTags");
+//! assert_eq!(doc.handle("9891f8a4-debd-488a-81cd-3d0f02345c74"), Some(tags));
+//! ```
+use std::collections::{HashMap, HashSet};
+use std::ops::{Index, IndexMut};
+
+use crate::element::{Attribute, Element, ElementHandle, ElementRef};
+use crate::referrers::ReferrerCache;
+use crate::span::{SourceMap, Span};
+use crate::visit::{repeated_segments, Kv2Path, PathSegment};
+use crate::{KV2Object, KV2Value};
+
+/// The value of an element's `id` attribute
+pub type ElementId = String;
+
+/// The `<!-- dmx encoding keyvalues2 1 format dmx 22 -->` comment at the top
+/// of a document
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Kv2Header {
+    pub encoding: String,
+    pub encoding_version: u32,
+    /// Empty for legacy `<!-- DMXVersion keyvalues2_v1 -->` headers
+    pub format: String,
+    pub format_version: u32,
+}
+
+impl Kv2Header {
+    /// Parses the text between `<!--` and `-->`, returns `None` if the
+    /// comment isn't a DMX header
+    pub(crate) fn from_comment(comment: &str) -> Option<Kv2Header> {
+        let words: Vec<&str> = comment.split_whitespace().collect();
+        match words.as_slice() {
+            ["dmx", "encoding", encoding, encoding_version, "format", format, format_version] => {
+                Some(Kv2Header {
+                    encoding: encoding.to_string(),
+                    encoding_version: encoding_version.parse().ok()?,
+                    format: format.to_string(),
+                    format_version: format_version.parse().ok()?,
+                })
+            }
+            ["DMXVersion", version] => {
+                let (encoding, encoding_version) = version.rsplit_once("_v")?;
+                Some(Kv2Header {
+                    encoding: encoding.to_string(),
+                    encoding_version: encoding_version.parse().ok()?,
+                    format: String::new(),
+                    format_version: 0,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Kv2Document {
+    header: Option<Kv2Header>,
+    /// Removed elements leave an empty slot so handles stay valid
+    elements: Vec<Option<Element>>,
+    roots: Vec<ElementHandle>,
+    /// Every element with an id, in handle order. References go to the
+    /// first one.
+    index: HashMap<ElementId, Vec<ElementHandle>>,
+    /// Built by the first [`Self::referrers`] call, edited elements are
+    /// re-indexed by the next one
+    referrers: ReferrerCache,
+    /// Spans of the source the document was parsed from
+    source_map: SourceMap,
+    /// Where parsed elements were in the source, to look up their spans
+    source_paths: HashMap<ElementHandle, Kv2Path>,
+}
+
+impl Kv2Document {
+    /// Builds a document from parsed root objects, adding every nested
+    /// object as an element and linking `element` references by id. If an id
+    /// appears more than once references go to the first element with it.
+    pub fn new(header: Option<Kv2Header>, roots: Vec<KV2Object>) -> Kv2Document {
+        let mut doc = Kv2Document {
+            header,
+            ..Default::default()
+        };
+        for object in roots {
+            let handle = doc.add_object(object, None);
+            doc.roots.push(handle);
+        }
+        doc.link_references();
+        doc
+    }
+
+    /// Builds a document like [`Self::new`], remembering where in the
+    /// source every element came from. Roots come with their position among
+    /// the roots of the source.
+    pub(crate) fn with_source_map(
+        header: Option<Kv2Header>,
+        roots: Vec<(usize, KV2Object)>,
+        source_map: SourceMap,
+    ) -> Kv2Document {
+        let mut doc = Kv2Document {
+            header,
+            source_map,
+            ..Default::default()
+        };
+        for (i, object) in roots {
+            let path = Kv2Path::new().join(PathSegment::Root(i));
+            let handle = doc.add_object(object, Some(path));
+            doc.roots.push(handle);
+        }
+        doc.link_references();
+        doc
+    }
+
+    /// Where an element is in the source it was parsed from, from its class
+    /// name to its closing `}`
+    ///
+    /// `None` for elements that weren't parsed from text. Spans describe the
+    /// source, edits to the document don't move them.
+    pub fn span(&self, handle: ElementHandle) -> Option<Span> {
+        self.element(handle)?;
+        self.source_map
+            .elements
+            .get(self.source_paths.get(&handle)?)
+            .copied()
+    }
+
+    /// Where an attribute of an element is in the source, from its key to
+    /// the end of its value
+    pub fn attribute_span(&self, handle: ElementHandle, attribute: &str) -> Option<Span> {
+        self.element(handle)?;
+        let path = self.source_paths.get(&handle)?;
+        let path = path.join(PathSegment::Attribute(attribute.to_string()));
+        self.source_map.values.get(&path).copied()
+    }
+
+    /// Where a later value of a repeated attribute is in the source, `1`
+    /// for the first one in [`Element::repeated`] with that name
+    ///
+    /// Only documents parsed with [`DuplicateKeyPolicy::KeepAll`] keep
+    /// repeated values.
+    ///
+    /// [`DuplicateKeyPolicy::KeepAll`]: crate::DuplicateKeyPolicy::KeepAll
+    pub fn repeated_span(
+        &self,
+        handle: ElementHandle,
+        attribute: &str,
+        occurrence: usize,
+    ) -> Option<Span> {
+        self.element(handle)?;
+        let path = self.source_paths.get(&handle)?;
+        let path = path.join(PathSegment::Repeated(attribute.to_string(), occurrence));
+        self.source_map.values.get(&path).copied()
+    }
+
+    /// Where an item of an array attribute is in the source
+    pub fn item_span(&self, handle: ElementHandle, attribute: &str, index: usize) -> Option<Span> {
+        self.element(handle)?;
+        let path = self.source_paths.get(&handle)?;
+        let path = path
+            .join(PathSegment::Attribute(attribute.to_string()))
+            .join(PathSegment::Index(index));
+        self.source_map.values.get(&path).copied()
+    }
+
+    pub fn header(&self) -> Option<&Kv2Header> {
+        self.header.as_ref()
+    }
+
+    pub fn roots(&self) -> &[ElementHandle] {
+        &self.roots
+    }
+
+    pub fn roots_mut(&mut self) -> &mut Vec<ElementHandle> {
+        &mut self.roots
+    }
+
+    /// Looks up any element in the document, root or nested, by its id
+    pub fn get(&self, id: &str) -> Option<&Element> {
+        self.element(self.handle(id)?)
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Option<&mut Element> {
+        self.element_mut(self.handle(id)?)
+    }
+
+    pub fn handle(&self, id: &str) -> Option<ElementHandle> {
+        self.index.get(id)?.first().copied()
+    }
+
+    /// Returns the element behind a handle, `None` if it was removed
+    pub fn element(&self, handle: ElementHandle) -> Option<&Element> {
+        self.elements.get(handle.0)?.as_ref()
+    }
+
+    pub fn element_mut(&mut self, handle: ElementHandle) -> Option<&mut Element> {
+        let element = self.elements.get_mut(handle.0)?.as_mut()?;
+        self.referrers.touch(handle);
+        Some(element)
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.index.contains_key(id)
+    }
+
+    /// Ids of every indexed element, in no particular order
+    pub fn ids(&self) -> impl Iterator<Item = &ElementId> {
+        self.index.keys()
+    }
+
+    /// Every element in the document, reachable from the roots or not
+    pub fn elements(&self) -> impl Iterator<Item = (ElementHandle, &Element)> {
+        self.elements
+            .iter()
+            .enumerate()
+            .filter_map(|(i, element)| Some((ElementHandle(i), element.as_ref()?)))
+    }
+
+    pub(crate) fn referrer_cache(&self) -> &ReferrerCache {
+        &self.referrers
+    }
+
+    /// Number of elements in the document
+    pub fn len(&self) -> usize {
+        self.elements.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds an empty element, which isn't referenced from anywhere yet
+    ///
+    /// Dangling references to `id` are linked to the new element. Pass an
+    /// empty id for an element without one.
+    pub fn add_element(
+        &mut self,
+        class_name: impl Into<String>,
+        id: impl Into<ElementId>,
+    ) -> ElementHandle {
+        let id = id.into();
+        let handle = self.push(Element {
+            class_name: class_name.into(),
+            id: id.clone(),
+            attributes: HashMap::new(),
+            repeated: Vec::new(),
+        });
+        if self.handle(&id) == Some(handle) {
+            self.link_id(&id, handle);
+        }
+        handle
+    }
+
+    /// Adds `object` and every object nested in it as elements and links
+    /// `element` references by id, returns the handle of `object`
+    pub fn insert_object(&mut self, object: KV2Object) -> ElementHandle {
+        let first_new = self.elements.len();
+        let handle = self.add_object(object, None);
+
+        let mut new_ids = Vec::new();
+        for i in first_new..self.elements.len() {
+            let Some(element) = self.elements[i].as_mut() else {
+                continue;
+            };
+            let index = &self.index;
+            for target in element.references_mut() {
+                if let ElementRef::Dangling(id) = target {
+                    if let Some(handle) = index.get(id).and_then(|handles| handles.first()) {
+                        *target = ElementRef::Handle(*handle);
+                    }
+                }
+            }
+            new_ids.push(element.id.clone());
+        }
+        // Only ids no older element has can resolve dangling references
+        for id in new_ids {
+            if let Some(first) = self.handle(&id).filter(|first| first.0 >= first_new) {
+                self.link_id(&id, first);
+            }
+        }
+        handle
+    }
+
+    /// Changes the id of an element, references to it follow along
+    pub fn set_id(&mut self, handle: ElementHandle, id: impl Into<ElementId>) {
+        let id = id.into();
+        let Some(element) = self.elements.get_mut(handle.0).and_then(Option::as_mut) else {
+            return;
+        };
+        let old = std::mem::replace(&mut element.id, id.clone());
+        self.unindex(&old, handle);
+        self.index_element(&id, handle);
+        if self
+            .index
+            .get(&id)
+            .is_some_and(|handles| handles == &[handle])
+        {
+            self.link_id(&id, handle);
+        }
+    }
+
+    /// Adds `handle` to the elements with `id`
+    fn index_element(&mut self, id: &str, handle: ElementHandle) {
+        if id.is_empty() {
+            return;
+        }
+        let handles = self.index.entry(id.to_string()).or_default();
+        if let Err(at) = handles.binary_search(&handle) {
+            handles.insert(at, handle);
+        }
+    }
+
+    /// Removes `handle` from the elements with `id`, the next one with the
+    /// id takes its place
+    fn unindex(&mut self, id: &str, handle: ElementHandle) {
+        let Some(handles) = self.index.get_mut(id) else {
+            return;
+        };
+        handles.retain(|h| *h != handle);
+        if handles.is_empty() {
+            self.index.remove(id);
+        }
+    }
+
+    /// Points the dangling references to `id` at `handle`
+    pub(crate) fn link_id(&mut self, id: &str, handle: ElementHandle) {
+        let holders = self
+            .referrers
+            .query(self, |index| index.dangling(id).collect::<Vec<_>>());
+        for holder in holders {
+            let Some(element) = self.element_mut(holder) else {
+                continue;
+            };
+            for target in element.references_mut() {
+                if matches!(target, ElementRef::Dangling(to) if to == id) {
+                    *target = ElementRef::Handle(handle);
+                }
+            }
+        }
+    }
+
+    /// Removes an element from the document and from the roots
+    ///
+    /// References to it move to the next element with its id, or become
+    /// dangling references to its id, or null references if it had none.
+    pub fn remove_element(&mut self, handle: ElementHandle) -> Option<Element> {
+        self.element(handle)?;
+        let holders: Vec<ElementHandle> = self
+            .referrers_of(handle)
+            .into_iter()
+            .map(|referrer| referrer.element)
+            .collect();
+        let element = self.elements.get_mut(handle.0)?.take()?;
+        self.referrers.touch(handle);
+        self.unindex(&element.id, handle);
+        self.roots.retain(|root| *root != handle);
+
+        // Another element with the same id takes over the references
+        let replacement = match self.handle(&element.id) {
+            _ if element.id.is_empty() => ElementRef::Null,
+            Some(other) => ElementRef::Handle(other),
+            None => ElementRef::Dangling(element.id.clone()),
+        };
+        for holder in holders {
+            let Some(other) = self.element_mut(holder) else {
+                continue;
+            };
+            for target in other.references_mut() {
+                if *target == ElementRef::Handle(handle) {
+                    *target = replacement.clone();
+                }
+            }
+        }
+
+        Some(element)
+    }
+
+    /// Returns every element reachable from `roots` through references,
+    /// including the roots themselves
+    pub fn reachable(&self, roots: &[ElementHandle]) -> HashSet<ElementHandle> {
+        let mut reachable = HashSet::new();
+        let mut pending: Vec<ElementHandle> = roots.to_vec();
+        while let Some(handle) = pending.pop() {
+            let Some(element) = self.element(handle) else {
+                continue;
+            };
+            if reachable.insert(handle) {
+                pending.extend(
+                    element
+                        .references()
+                        .filter_map(|(_, _, target)| target.handle()),
+                );
+            }
+        }
+        reachable
+    }
+
+    /// Removes every element that can't be reached from the document roots,
+    /// returns the removed elements
+    pub fn collect_garbage(&mut self) -> Vec<(ElementHandle, Element)> {
+        let roots = self.roots.clone();
+        self.collect_garbage_from(&roots)
+    }
+
+    /// Removes every element that can't be reached from `roots`, returns the
+    /// removed elements
+    ///
+    /// Document roots that aren't reachable from `roots` are removed too.
+    pub fn collect_garbage_from(
+        &mut self,
+        roots: &[ElementHandle],
+    ) -> Vec<(ElementHandle, Element)> {
+        let reachable = self.reachable(roots);
+
+        // Only unreachable elements can refer to unreachable elements, so
+        // removing them leaves no dangling references behind
+        let mut removed = Vec::new();
+        for (i, slot) in self.elements.iter_mut().enumerate() {
+            let handle = ElementHandle(i);
+            if slot.is_some() && !reachable.contains(&handle) {
+                if let Some(element) = slot.take() {
+                    self.referrers.touch(handle);
+                    removed.push((handle, element));
+                }
+            }
+        }
+
+        for (handle, element) in &removed {
+            self.unindex(&element.id, *handle);
+        }
+        self.roots.retain(|root| reachable.contains(root));
+
+        removed
+    }
+
+    /// Renames element ids according to `ids`, old id to new id
+    ///
+    /// References by handle follow their elements, dangling references to a
+    /// renamed id are renamed too. Ids missing from `ids` are kept.
+    pub fn remap_ids(&mut self, ids: &HashMap<ElementId, ElementId>) {
+        for (i, element) in self.elements.iter_mut().enumerate() {
+            let Some(element) = element else {
+                continue;
+            };
+            if let Some(id) = ids.get(&element.id) {
+                element.id = id.clone();
+            }
+            for target in element.references_mut() {
+                if let ElementRef::Dangling(id) = target {
+                    if let Some(new_id) = ids.get(id) {
+                        *id = new_id.clone();
+                        self.referrers.touch(ElementHandle(i));
+                    }
+                }
+            }
+        }
+
+        self.rebuild_index();
+        self.link_references();
+    }
+
+    /// Gives every element that has an id a new random one, returns the old
+    /// ids mapped to the new ones
+    pub fn regenerate_ids(&mut self) -> HashMap<ElementId, ElementId> {
+        // References by handle stay as they are, so do the referrers
+        let mut ids = HashMap::new();
+        let mut used: HashSet<ElementId> = self.index.keys().cloned().collect();
+        for element in self.elements.iter_mut().flatten() {
+            if element.id.is_empty() {
+                continue;
+            }
+            let new_id = loop {
+                let id = generate_element_id();
+                if used.insert(id.clone()) {
+                    break id;
+                }
+            };
+            let old_id = std::mem::replace(&mut element.id, new_id.clone());
+            ids.entry(old_id).or_insert(new_id);
+        }
+
+        // Dangling references keep pointing at ids outside the document
+        self.rebuild_index();
+        ids
+    }
+
+    /// Finds every reference to an id that no element in the document has
+    pub fn dangling_references(&self) -> Vec<DanglingReference> {
+        let mut dangling = Vec::new();
+        for (handle, element) in self.elements() {
+            for (attribute, index, target) in element.references() {
+                if let ElementRef::Dangling(target) = target {
+                    dangling.push(DanglingReference {
+                        element: handle,
+                        attribute: attribute.to_string(),
+                        index,
+                        target: target.clone(),
+                    });
+                }
+            }
+        }
+        dangling
+    }
+
+    /// Converts the document back to owned trees, one per root
+    ///
+    /// Each element is written inline at its first reference, in attribute
+    /// name order, and referenced by id after that. Elements without an id
+    /// are written inline at every reference.
+    pub fn to_objects(&self) -> Vec<KV2Object> {
+        let mut written: HashSet<ElementHandle> = self.roots.iter().copied().collect();
+        self.roots
+            .iter()
+            .filter_map(|root| Some(self.to_object(self.element(*root)?, &mut written)))
+            .collect()
+    }
+
+    fn to_object(&self, element: &Element, written: &mut HashSet<ElementHandle>) -> KV2Object {
+        let mut fields = HashMap::new();
+        if !element.id.is_empty() {
+            fields.insert("id".to_string(), KV2Value::String(element.id.clone()));
+        }
+
+        let mut names: Vec<&String> = element.attributes.keys().collect();
+        names.sort();
+        for name in names {
+            let value = self.attribute_to_value(&element.attributes[name], written);
+            fields.insert(name.clone(), value);
+        }
+        let repeated = element
+            .repeated
+            .iter()
+            .map(|(name, attribute)| (name.clone(), self.attribute_to_value(attribute, written)))
+            .collect();
+
+        KV2Object {
+            class_name: element.class_name.clone(),
+            fields,
+            repeated,
+        }
+    }
+
+    fn attribute_to_value(
+        &self,
+        attribute: &Attribute,
+        written: &mut HashSet<ElementHandle>,
+    ) -> KV2Value {
+        match attribute {
+            Attribute::Value(value) => value.clone(),
+            Attribute::Element(target) => self.ref_to_value(target, written),
+            Attribute::ElementArray(targets) => KV2Value::Array(
+                targets
+                    .iter()
+                    .map(|target| self.ref_to_value(target, written))
+                    .collect(),
+            ),
+        }
+    }
+
+    fn ref_to_value(&self, target: &ElementRef, written: &mut HashSet<ElementHandle>) -> KV2Value {
+        let handle = match target {
+            ElementRef::Null => return KV2Value::Element(String::new()),
+            ElementRef::Dangling(id) => return KV2Value::Element(id.clone()),
+            ElementRef::Handle(handle) => *handle,
+        };
+        let Some(element) = self.element(handle) else {
+            return KV2Value::Element(String::new());
+        };
+
+        if !written.insert(handle) {
+            // Already written, or an element without id currently being
+            // written further up, which can't be referenced
+            return KV2Value::Element(element.id.clone());
+        }
+        let object = self.to_object(element, written);
+        if element.id.is_empty() {
+            written.remove(&handle);
+        }
+        KV2Value::Object(object)
+    }
+
+    pub(crate) fn push(&mut self, element: Element) -> ElementHandle {
+        let handle = ElementHandle(self.elements.len());
+        self.referrers.touch(handle);
+        self.index_element(&element.id, handle);
+        self.elements.push(Some(element));
+        handle
+    }
+
+    /// Adds an object and its nested objects without linking references,
+    /// `path` is where the object was in the source if it was parsed
+    fn add_object(&mut self, object: KV2Object, path: Option<Kv2Path>) -> ElementHandle {
+        let KV2Object {
+            class_name,
+            mut fields,
+            repeated,
+        } = object;
+        let id = match fields.remove("id") {
+            Some(KV2Value::String(id)) => id,
+            Some(other) => {
+                fields.insert("id".to_string(), other);
+                String::new()
+            }
+            None => String::new(),
+        };
+
+        // Reserve the slot first so parents come before their children
+        let handle = self.push(Element {
+            class_name,
+            id,
+            attributes: HashMap::new(),
+            repeated: Vec::new(),
+        });
+
+        let attributes = fields
+            .into_iter()
+            .map(|(name, value)| {
+                let at = |path: &Kv2Path| path.join(PathSegment::Attribute(name.clone()));
+                let attribute = self.convert_value(value, path.as_ref().map(at));
+                (name, attribute)
+            })
+            .collect();
+        let segments: Vec<PathSegment> = repeated_segments(&repeated).collect();
+        let repeated = repeated
+            .into_iter()
+            .zip(segments)
+            .map(|((name, value), segment)| {
+                let attribute = self.convert_value(value, path.as_ref().map(|p| p.join(segment)));
+                (name, attribute)
+            })
+            .collect();
+        if let Some(element) = self.element_mut(handle) {
+            element.attributes = attributes;
+            element.repeated = repeated;
+        }
+        if let Some(path) = path {
+            self.source_paths.insert(handle, path);
+        }
+        handle
+    }
+
+    fn convert_value(&mut self, value: KV2Value, path: Option<Kv2Path>) -> Attribute {
+        match value {
+            KV2Value::Object(_) | KV2Value::Element(_) => {
+                Attribute::Element(self.convert_ref(value, path))
+            }
+            // Empty arrays are written as element arrays, so read them as such
+            KV2Value::Array(values)
+                if values.is_empty()
+                    || values
+                        .iter()
+                        .any(|v| matches!(v, KV2Value::Object(_) | KV2Value::Element(_))) =>
+            {
+                let at = |path: &Kv2Path, i| path.join(PathSegment::Index(i));
+                let targets = values
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, v)| self.convert_ref(v, path.as_ref().map(|path| at(path, i))))
+                    .collect();
+                Attribute::ElementArray(targets)
+            }
+            value => Attribute::Value(value),
+        }
+    }
+
+    /// References by id start out dangling until [`Self::link_references`]
+    fn convert_ref(&mut self, value: KV2Value, path: Option<Kv2Path>) -> ElementRef {
+        match value {
+            KV2Value::Object(object) => ElementRef::Handle(self.add_object(object, path)),
+            KV2Value::Element(id) if !id.is_empty() => ElementRef::Dangling(id),
+            _ => ElementRef::Null,
+        }
+    }
+
+    fn rebuild_index(&mut self) {
+        self.index.clear();
+        for (i, element) in self.elements.iter().enumerate() {
+            if let Some(element) = element {
+                if !element.id.is_empty() {
+                    self.index
+                        .entry(element.id.clone())
+                        .or_default()
+                        .push(ElementHandle(i));
+                }
+            }
+        }
+    }
+
+    pub(crate) fn link_references(&mut self) {
+        let index = &self.index;
+        for (i, element) in self.elements.iter_mut().enumerate() {
+            let Some(element) = element else {
+                continue;
+            };
+            for target in element.references_mut() {
+                if let ElementRef::Dangling(id) = target {
+                    if let Some(handle) = index.get(id).and_then(|handles| handles.first()) {
+                        *target = ElementRef::Handle(*handle);
+                        self.referrers.touch(ElementHandle(i));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Index<ElementHandle> for Kv2Document {
+    type Output = Element;
+
+    /// Panics if the element was removed
+    fn index(&self, handle: ElementHandle) -> &Element {
+        self.element(handle)
+            .expect("element was removed from the document")
+    }
+}
+
+impl IndexMut<ElementHandle> for Kv2Document {
+    fn index_mut(&mut self, handle: ElementHandle) -> &mut Element {
+        self.element_mut(handle)
+            .expect("element was removed from the document")
+    }
+}
+
+/// Generates a random version 4 UUID, the format DMX uses for element ids
+pub fn generate_element_id() -> ElementId {
+    let high = random_u64();
+    let low = random_u64();
+    // Version 4 in the third group, RFC 4122 variant in the fourth
+    let high = (high & !0xf000) | 0x4000;
+    let low = (low & !(0b11 << 62)) | (0b10 << 62);
+
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0xffff,
+        low >> 48,
+        low & 0xffff_ffff_ffff
+    )
+}
+
+fn random_u64() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    // Every RandomState is seeded differently, mix in a counter and the time
+    // so ids stay distinct even if the seeds repeat
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    if let Ok(time) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        hasher.write_u128(time.as_nanos());
+    }
+    hasher.finish()
+}
+
+/// A reference to an element id that isn't defined in the document
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DanglingReference {
+    /// The element holding the reference
+    pub element: ElementHandle,
+    pub attribute: String,
+    /// Position in the `element_array`, if the reference is an array item
+    pub index: Option<usize>,
+    /// The id that couldn't be found
+    pub target: ElementId,
+}
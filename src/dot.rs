@@ -0,0 +1,207 @@
+//! Exporting the element graph of a document as Graphviz DOT
+//!
+//! # Example
+//! ```rust
+//! use kv2::{parse_kv2_document, DotOptions};
+//!
+//! let (_, doc) = parse_kv2_document(r#"
+//! "DmeModel"
+//! {
+//! "id" "elementid" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+//! "name" "string" "body"
+//! "transform" "DmeTransform"
+//! {
+//!     "id" "elementid" "56f186a9-1316-46c1-b82d-f46d5f19e19e"
+//!     "position" "vector3" "0 0 0"
+//! }
+//! }
+//! "#).unwrap();
+//!
+//! let dot = doc.to_dot();
+//! assert!(dot.contains(r#"e0 -> e1 [label="transform"];"#));
+//!
+//! let dot = doc.to_dot_with(&DotOptions {
+//!     collapse_values: true,
+//!     ..Default::default()
+//! });
+//! assert!(!dot.contains("position"));
+//! ```
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt::Write;
+
+use crate::document::Kv2Document;
+use crate::element::{Attribute, Element, ElementHandle, ElementRef};
+use crate::writer::{format_float, FloatSpelling};
+use crate::KV2Value;
+
+/// What [`Kv2Document::to_dot_with`] includes in the graph
+#[derive(Debug, Clone, Default)]
+pub struct DotOptions {
+    /// Leave attributes that aren't element references out of the node
+    /// labels, which then only show the class and `name`
+    pub collapse_values: bool,
+    /// Only include elements at most this many references away from the
+    /// roots
+    pub max_depth: Option<usize>,
+    /// Start from these elements instead of the document roots
+    pub roots: Option<Vec<ElementHandle>>,
+}
+
+impl Kv2Document {
+    /// Writes every element as a node and every reference as an edge, see
+    /// [`to_dot_with`](Self::to_dot_with)
+    pub fn to_dot(&self) -> String {
+        self.to_dot_with(&DotOptions::default())
+    }
+
+    /// Writes the element graph as a Graphviz `digraph`
+    ///
+    /// Nodes are named `e<handle index>` and labelled with the class name,
+    /// the `name` attribute and unless collapsed the other values. Edges are
+    /// labelled with the attribute name, and the index for `element_array`
+    /// items. Dangling references point at dashed nodes labelled with the
+    /// missing id.
+    ///
+    /// Without `max_depth` or `roots` every element is included, reachable
+    /// or not, otherwise only those reachable from the roots within the
+    /// depth limit.
+    pub fn to_dot_with(&self, options: &DotOptions) -> String {
+        let included = self.dot_elements(options);
+
+        let mut out = String::from("digraph kv2 {\n");
+        out.push_str("\tnode [shape=box];\n");
+
+        let mut handles: Vec<ElementHandle> = included.keys().copied().collect();
+        handles.sort();
+        for handle in &handles {
+            let label = dot_label(&self[*handle], options.collapse_values);
+            let _ = writeln!(out, "\te{} [label=\"{}\"];", handle.index(), label);
+        }
+
+        let mut dangling = BTreeSet::new();
+        for handle in &handles {
+            let element = &self[*handle];
+            let mut references: Vec<_> = element.references().collect();
+            references.sort_by_key(|(attribute, index, _)| (*attribute, *index));
+            for (attribute, index, target) in references {
+                let target = match target {
+                    ElementRef::Null => continue,
+                    ElementRef::Handle(target) if included.contains_key(target) => {
+                        format!("e{}", target.index())
+                    }
+                    ElementRef::Handle(_) => continue,
+                    ElementRef::Dangling(id) => {
+                        dangling.insert(id.as_str());
+                        format!("\"{}\"", escape(id))
+                    }
+                };
+                let label = match index {
+                    Some(index) => format!("{}[{}]", attribute, index),
+                    None => attribute.to_string(),
+                };
+                let _ = writeln!(
+                    out,
+                    "\te{} -> {} [label=\"{}\"];",
+                    handle.index(),
+                    target,
+                    escape(&label)
+                );
+            }
+        }
+
+        for id in dangling {
+            let _ = writeln!(out, "\t\"{}\" [style=dashed];", escape(id));
+        }
+
+        out.push_str("}\n");
+        out
+    }
+
+    /// The elements to draw, with their distance from the nearest root
+    fn dot_elements(&self, options: &DotOptions) -> HashMap<ElementHandle, usize> {
+        if options.roots.is_none() && options.max_depth.is_none() {
+            return self.elements().map(|(handle, _)| (handle, 0)).collect();
+        }
+
+        let roots = options.roots.as_deref().unwrap_or(self.roots());
+        let mut depths = HashMap::new();
+        let mut pending: VecDeque<(ElementHandle, usize)> =
+            roots.iter().map(|root| (*root, 0)).collect();
+        while let Some((handle, depth)) = pending.pop_front() {
+            let Some(element) = self.element(handle) else {
+                continue;
+            };
+            if depths.contains_key(&handle) {
+                continue;
+            }
+            depths.insert(handle, depth);
+            if options.max_depth.is_some_and(|max| depth >= max) {
+                continue;
+            }
+            pending.extend(
+                element
+                    .references()
+                    .filter_map(|(_, _, target)| Some((target.handle()?, depth + 1))),
+            );
+        }
+        depths
+    }
+}
+
+fn dot_label(element: &Element, collapse_values: bool) -> String {
+    let mut lines = vec![element.class_name.clone()];
+    if let Some(name) = element.name() {
+        lines.push(format!("\"{}\"", name));
+    }
+
+    if !collapse_values {
+        let mut names: Vec<&String> = element.attributes.keys().collect();
+        names.sort();
+        for name in names {
+            if let Attribute::Value(value) = &element.attributes[name] {
+                if name != "name" || element.name().is_none() {
+                    lines.push(format!("{} = {}", name, format_value(value)));
+                }
+            }
+        }
+    }
+
+    lines
+        .iter()
+        .map(|line| escape(line))
+        .collect::<Vec<_>>()
+        .join("\\n")
+}
+
+fn format_value(value: &KV2Value) -> String {
+    match value {
+        KV2Value::Bool(b) => b.to_string(),
+        KV2Value::Int(i) => i.to_string(),
+        KV2Value::Double(d) => format_float(*d, FloatSpelling::Portable),
+        KV2Value::Vector(v) | KV2Value::Quaternion(v) => v
+            .iter()
+            .map(|d| format_float(*d, FloatSpelling::Portable))
+            .collect::<Vec<_>>()
+            .join(" "),
+        KV2Value::String(s) => format!("\"{}\"", s),
+        KV2Value::Element(id) => id.clone(),
+        KV2Value::Array(items) => format!("[{} items]", items.len()),
+        KV2Value::Object(object) => object.class_name.clone(),
+    }
+}
+
+/// Escapes text for a quoted DOT string
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,152 @@
+//! Elements stored in a [`Kv2Document`](crate::Kv2Document)
+//!
+//! Unlike [`KV2Object`](crate::KV2Object), which owns its nested objects,
+//! an [`Element`] refers to other elements through [`ElementHandle`]s, so an
+//! element referenced from many places exists once and cycles are fine.
+use std::collections::HashMap;
+
+use crate::document::ElementId;
+use crate::KV2Value;
+
+/// Index of an element in its document
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ElementHandle(pub(crate) usize);
+
+impl ElementHandle {
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// The target of an `element` attribute or `element_array` item
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ElementRef {
+    /// An empty reference, written as `""`
+    Null,
+    Handle(ElementHandle),
+    /// A reference to an id no element in the document has
+    Dangling(ElementId),
+}
+
+impl ElementRef {
+    pub fn handle(&self) -> Option<ElementHandle> {
+        match self {
+            ElementRef::Handle(handle) => Some(*handle),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Attribute {
+    /// Any value that isn't an element reference
+    Value(KV2Value),
+    Element(ElementRef),
+    ElementArray(Vec<ElementRef>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Element {
+    pub class_name: String,
+    pub(crate) id: ElementId,
+    pub attributes: HashMap<String, Attribute>,
+    /// Later values of attributes that appear more than once, see
+    /// [`KV2Object::repeated`](crate::KV2Object::repeated)
+    pub repeated: Vec<(String, Attribute)>,
+}
+
+impl Element {
+    /// The element's id, empty if it has none. Use
+    /// [`Kv2Document::set_id`](crate::Kv2Document::set_id) to change it.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Attribute> {
+        self.attributes.get(name)
+    }
+
+    /// Returns the value of a non-element attribute
+    pub fn value(&self, name: &str) -> Option<&KV2Value> {
+        match self.attributes.get(name)? {
+            Attribute::Value(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of the `name` attribute, if it is a string
+    pub fn name(&self) -> Option<&str> {
+        match self.value("name")? {
+            KV2Value::String(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Returns the element an `element` attribute points at
+    pub fn child(&self, name: &str) -> Option<ElementHandle> {
+        match self.attributes.get(name)? {
+            Attribute::Element(target) => target.handle(),
+            _ => None,
+        }
+    }
+
+    /// Returns the elements an `element_array` attribute points at,
+    /// skipping null and dangling references
+    pub fn children(&self, name: &str) -> Vec<ElementHandle> {
+        match self.attributes.get(name) {
+            Some(Attribute::ElementArray(targets)) => {
+                targets.iter().filter_map(ElementRef::handle).collect()
+            }
+            Some(Attribute::Element(target)) => target.handle().into_iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn set(&mut self, name: impl Into<String>, attribute: Attribute) -> Option<Attribute> {
+        self.attributes.insert(name.into(), attribute)
+    }
+
+    pub fn set_value(&mut self, name: impl Into<String>, value: KV2Value) -> Option<Attribute> {
+        self.set(name, Attribute::Value(value))
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Attribute> {
+        self.attributes.remove(name)
+    }
+
+    /// Every reference held by the element, with the attribute name and the
+    /// array index for `element_array` items, repeated attributes included
+    pub fn references(&self) -> impl Iterator<Item = (&str, Option<usize>, &ElementRef)> {
+        let repeated = self
+            .repeated
+            .iter()
+            .map(|(name, attribute)| (name, attribute));
+        self.attributes.iter().chain(repeated).flat_map(
+            |(name, attribute)| -> Vec<(&str, Option<usize>, &ElementRef)> {
+                match attribute {
+                    Attribute::Element(target) => vec![(name.as_str(), None, target)],
+                    Attribute::ElementArray(targets) => targets
+                        .iter()
+                        .enumerate()
+                        .map(|(i, target)| (name.as_str(), Some(i), target))
+                        .collect(),
+                    Attribute::Value(_) => Vec::new(),
+                }
+            },
+        )
+    }
+
+    pub(crate) fn references_mut(&mut self) -> impl Iterator<Item = &mut ElementRef> {
+        let repeated = self.repeated.iter_mut().map(|(_, attribute)| attribute);
+        self.attributes
+            .values_mut()
+            .chain(repeated)
+            .flat_map(|attribute| -> Vec<&mut ElementRef> {
+                match attribute {
+                    Attribute::Element(target) => vec![target],
+                    Attribute::ElementArray(targets) => targets.iter_mut().collect(),
+                    Attribute::Value(_) => Vec::new(),
+                }
+            })
+    }
+}
@@ -0,0 +1,235 @@
+//! Parsing raw bytes in the encodings KV2 files are found in
+//!
+//! # Example
+//! ```rust
+//! use kv2::{parse_kv2_bytes, KV2Value};
+//!
+//! // Windows-1252, as written by older tools
+//! let input = b"\"DmElement\"\n{\n\"name\" \"string\" \"caf\xe9\"\n}\n";
+//! let objects = parse_kv2_bytes(input).unwrap();
+//! assert_eq!(objects[0].fields["name"], KV2Value::String("caf\u{e9}".to_string()));
+//! ```
+use std::fmt;
+
+use crate::error::Kv2Error;
+use crate::{parse_kv2_complete, KV2Object};
+
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+const UTF16LE_BOM: &[u8] = b"\xff\xfe";
+const UTF16BE_BOM: &[u8] = b"\xfe\xff";
+
+/// Characters of Windows-1252 bytes 0x80 to 0x9f, `None` where undefined.
+/// The other bytes map to the code point of the same value.
+const WINDOWS_1252_HIGH: [Option<char>; 32] = [
+    Some('\u{20ac}'),
+    None,
+    Some('\u{201a}'),
+    Some('\u{0192}'),
+    Some('\u{201e}'),
+    Some('\u{2026}'),
+    Some('\u{2020}'),
+    Some('\u{2021}'),
+    Some('\u{02c6}'),
+    Some('\u{2030}'),
+    Some('\u{0160}'),
+    Some('\u{2039}'),
+    Some('\u{0152}'),
+    None,
+    Some('\u{017d}'),
+    None,
+    None,
+    Some('\u{2018}'),
+    Some('\u{2019}'),
+    Some('\u{201c}'),
+    Some('\u{201d}'),
+    Some('\u{2022}'),
+    Some('\u{2013}'),
+    Some('\u{2014}'),
+    Some('\u{02dc}'),
+    Some('\u{2122}'),
+    Some('\u{0161}'),
+    Some('\u{203a}'),
+    Some('\u{0153}'),
+    None,
+    Some('\u{017e}'),
+    Some('\u{0178}'),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    /// The Western European code page older Windows tools write
+    Windows1252,
+}
+
+impl Encoding {
+    /// Picks the encoding from the byte order mark, or UTF-8 if the input
+    /// is valid UTF-8 and Windows-1252 if it isn't
+    pub fn detect(input: &[u8]) -> Encoding {
+        if input.starts_with(UTF8_BOM) {
+            Encoding::Utf8
+        } else if input.starts_with(UTF16LE_BOM) {
+            Encoding::Utf16Le
+        } else if input.starts_with(UTF16BE_BOM) {
+            Encoding::Utf16Be
+        } else if std::str::from_utf8(input).is_ok() {
+            Encoding::Utf8
+        } else {
+            Encoding::Windows1252
+        }
+    }
+
+    fn bom(self) -> &'static [u8] {
+        match self {
+            Encoding::Utf8 => UTF8_BOM,
+            Encoding::Utf16Le => UTF16LE_BOM,
+            Encoding::Utf16Be => UTF16BE_BOM,
+            Encoding::Windows1252 => b"",
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Encoding::Utf8 => write!(f, "UTF-8"),
+            Encoding::Utf16Le => write!(f, "UTF-16LE"),
+            Encoding::Utf16Be => write!(f, "UTF-16BE"),
+            Encoding::Windows1252 => write!(f, "Windows-1252"),
+        }
+    }
+}
+
+/// Bytes that aren't valid in the encoding they were read as
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    pub encoding: Encoding,
+    /// Byte offset of the invalid sequence in the input
+    pub offset: usize,
+    /// Length of the invalid sequence in bytes
+    pub len: usize,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid {} sequence of {} bytes at byte {}",
+            self.encoding, self.len, self.offset
+        )
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Why [`parse_kv2_bytes`] failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BytesError {
+    Decode(DecodeError),
+    /// A syntax error, with the offset counted in bytes of the input
+    Parse(Kv2Error),
+}
+
+impl fmt::Display for BytesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BytesError::Decode(error) => write!(f, "{}", error),
+            BytesError::Parse(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for BytesError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BytesError::Decode(error) => Some(error),
+            BytesError::Parse(error) => Some(error),
+        }
+    }
+}
+
+/// Parses root objects from bytes in the encoding [`Encoding::detect`]
+/// picks, requiring the whole input to be consumed
+pub fn parse_kv2_bytes(input: &[u8]) -> Result<Vec<KV2Object>, BytesError> {
+    parse_kv2_bytes_as(input, Encoding::detect(input))
+}
+
+/// Parses root objects from bytes in `encoding`, skipping its byte order
+/// mark if there is one and requiring the whole input to be consumed
+pub fn parse_kv2_bytes_as(input: &[u8], encoding: Encoding) -> Result<Vec<KV2Object>, BytesError> {
+    let text = decode(input, encoding).map_err(BytesError::Decode)?;
+    parse_kv2_complete(&text).map_err(|mut error| {
+        error.offset = byte_offset(input, &text, encoding, error.offset);
+        BytesError::Parse(error)
+    })
+}
+
+/// Decodes `input` to a string, skipping the byte order mark of `encoding`
+pub fn decode(input: &[u8], encoding: Encoding) -> Result<String, DecodeError> {
+    let bom = encoding.bom();
+    let skipped = if input.starts_with(bom) { bom.len() } else { 0 };
+    let bytes = &input[skipped..];
+    let invalid = |offset: usize, len: usize| DecodeError {
+        encoding,
+        offset: skipped + offset,
+        len,
+    };
+
+    match encoding {
+        Encoding::Utf8 => match std::str::from_utf8(bytes) {
+            Ok(text) => Ok(text.to_string()),
+            Err(e) => {
+                let len = e.error_len().unwrap_or(bytes.len() - e.valid_up_to());
+                Err(invalid(e.valid_up_to(), len))
+            }
+        },
+        Encoding::Utf16Le | Encoding::Utf16Be => {
+            // `is_multiple_of` needs Rust 1.87
+            #[allow(clippy::manual_is_multiple_of)]
+            if bytes.len() % 2 != 0 {
+                return Err(invalid(bytes.len() - 1, 1));
+            }
+            let units = bytes.chunks_exact(2).map(|pair| match encoding {
+                Encoding::Utf16Le => u16::from_le_bytes([pair[0], pair[1]]),
+                _ => u16::from_be_bytes([pair[0], pair[1]]),
+            });
+            let mut text = String::with_capacity(bytes.len() / 2);
+            let mut offset = 0;
+            for c in char::decode_utf16(units) {
+                match c {
+                    Ok(c) => {
+                        text.push(c);
+                        offset += c.len_utf16() * 2;
+                    }
+                    Err(_) => return Err(invalid(offset, 2)),
+                }
+            }
+            Ok(text)
+        }
+        Encoding::Windows1252 => bytes
+            .iter()
+            .enumerate()
+            .map(|(offset, byte)| match byte {
+                0x80..=0x9f => {
+                    WINDOWS_1252_HIGH[usize::from(byte - 0x80)].ok_or_else(|| invalid(offset, 1))
+                }
+                _ => Ok(char::from(*byte)),
+            })
+            .collect(),
+    }
+}
+
+/// Converts a byte offset in the decoded text back to one in the input
+fn byte_offset(input: &[u8], text: &str, encoding: Encoding, offset: usize) -> usize {
+    let bom = encoding.bom();
+    let skipped = if input.starts_with(bom) { bom.len() } else { 0 };
+    let before = &text[..offset.min(text.len())];
+    skipped
+        + match encoding {
+            Encoding::Utf8 => before.len(),
+            Encoding::Utf16Le | Encoding::Utf16Be => before.encode_utf16().count() * 2,
+            Encoding::Windows1252 => before.chars().count(),
+        }
+}
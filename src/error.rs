@@ -0,0 +1,116 @@
+//! Errors returned by the parser
+//!
+//! # Example
+//! ```rust
+//! use kv2::{parse_kv2, Expected};
+//!
+//! let error = parse_kv2(r#"
+//! "DmElement"
+//! {
+//! "id" "elementid" "df939bf4-8dd6-435c-9eef-a6e25434ecca"
+//! "name" "string" root
+//! }
+//! "#).unwrap_err();
+//!
+//! assert_eq!((error.line, error.column), (5, 17));
+//! assert!(error.expected.contains(&Expected::QuotedString));
+//! assert_eq!(error.path.to_string(), "[0]/name");
+//! ```
+use std::fmt;
+
+use crate::visit::Kv2Path;
+
+/// Something the parser was looking for when it failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Expected {
+    /// Punctuation like `{`, `}`, `[` or `,`
+    Token(&'static str),
+    QuotedString,
+    EndOfInput,
+    /// An attribute name not used earlier in the element, with
+    /// [`DuplicateKeyPolicy::Error`](crate::DuplicateKeyPolicy::Error)
+    UniqueAttribute,
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expected::Token(token) => write!(f, "{:?}", token),
+            Expected::QuotedString => write!(f, "a quoted string"),
+            Expected::EndOfInput => write!(f, "end of input"),
+            Expected::UniqueAttribute => write!(f, "an attribute not already in the element"),
+        }
+    }
+}
+
+/// Why and where parsing failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Kv2Error {
+    /// 1-based line of the failure
+    pub line: usize,
+    /// 1-based column of the failure, in characters
+    pub column: usize,
+    /// Byte offset of the failure in the input
+    pub offset: usize,
+    /// Everything that would have let parsing continue at `offset`
+    pub expected: Vec<Expected>,
+    /// The element and attribute being parsed, like `[0]/children[1]/name`
+    pub path: Kv2Path,
+}
+
+impl Kv2Error {
+    pub(crate) fn new(
+        source: &str,
+        offset: usize,
+        expected: Vec<Expected>,
+        path: Kv2Path,
+    ) -> Kv2Error {
+        let (line, column) = line_column(source, offset);
+        Kv2Error {
+            line,
+            column,
+            offset,
+            expected,
+            path,
+        }
+    }
+
+    /// `expected "}" or a quoted string`, without the position and path
+    pub(crate) fn describe_expected(&self) -> String {
+        match self.expected.as_slice() {
+            [] => "unexpected input".to_string(),
+            [only] => format!("expected {}", only),
+            [rest @ .., last] => {
+                let rest: Vec<String> = rest.iter().map(Expected::to_string).collect();
+                format!("expected {} or {}", rest.join(", "), last)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Kv2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.line,
+            self.column,
+            self.describe_expected()
+        )?;
+        if !self.path.is_empty() {
+            write!(f, " in {}", self.path)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Kv2Error {}
+
+/// 1-based line and column of a byte offset in `source`
+pub(crate) fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let line = before.matches('\n').count() + 1;
+    let column = before[line_start..].chars().count() + 1;
+    (line, column)
+}
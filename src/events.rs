@@ -0,0 +1,307 @@
+//! Reading KV2 as a stream of events without building a tree
+//!
+//! Tools that only need a few attributes out of a large file can pick them
+//! out of the events and skip everything else.
+//!
+//! # Example
+//! ```rust
+//! use kv2::{Kv2Event, Kv2Events};
+//!
+//! let input = r#"
+//! "DmElement"
+//! {
+//! "name" "string" "root"
+//! "values" "int_array" [ "1", "2" ]
+//! }
+//! "#;
+//!
+//! let names: Vec<String> = Kv2Events::new(input.as_bytes())
+//!     .filter_map(|event| match event.unwrap() {
+//!         Kv2Event::Attribute { key, raw, .. } if key == "name" => Some(raw),
+//!         _ => None,
+//!     })
+//!     .collect();
+//! assert_eq!(names, ["root"]);
+//! ```
+use std::io::BufRead;
+
+use crate::error::{Expected, Kv2Error};
+use crate::reader::{Position, ReadError, Token, Tokenizer};
+use crate::visit::{Kv2Path, PathSegment};
+use crate::ParseOptions;
+
+/// Something found in the input, in document order
+///
+/// Every `StartElement` is matched by an `EndElement` and every
+/// `StartArray` by an `EndArray`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Kv2Event {
+    /// An element starts, `key` is `None` for roots and array items
+    StartElement {
+        key: Option<String>,
+        class: String,
+    },
+    /// A `"key" "type" "value"` attribute, `raw` is the unconverted value
+    Attribute {
+        key: String,
+        data_type: String,
+        raw: String,
+    },
+    /// An array attribute starts, `data_type` ends in `_array`
+    StartArray {
+        key: String,
+        data_type: String,
+    },
+    /// A value in an array. `key` is set for `"element" "<id>"` style
+    /// pairs in element arrays.
+    Item {
+        key: Option<String>,
+        raw: String,
+    },
+    EndArray,
+    EndElement,
+    /// The text of a `<!-- -->` comment, or of a `//` comment if enabled,
+    /// without the delimiters. Comments inside an attribute come after it.
+    Comment(String),
+}
+
+/// What the events are currently inside of
+enum Frame {
+    Element,
+    Array { elements: bool, items: usize },
+}
+
+/// Iterator over the [`Kv2Event`]s of KV2 text read from `R`
+///
+/// The input has to be UTF-8, a byte order mark is skipped. After an error
+/// the iterator ends.
+pub struct Kv2Events<R> {
+    tokens: Tokenizer<R>,
+    stack: Vec<Frame>,
+    /// Where the next event is, each frame owns its last segment
+    path: Kv2Path,
+    roots: usize,
+    /// Where the first token of the last event is
+    start: Position,
+    started: bool,
+    done: bool,
+}
+
+impl<R: BufRead> Kv2Events<R> {
+    pub fn new(reader: R) -> Kv2Events<R> {
+        Kv2Events::with_options(reader, &ParseOptions::default())
+    }
+
+    /// Reads events accepting the syntax enabled in `options`
+    pub fn with_options(reader: R, options: &ParseOptions) -> Kv2Events<R> {
+        Kv2Events {
+            tokens: Tokenizer::new(reader, options),
+            stack: Vec::new(),
+            path: Kv2Path::new(),
+            roots: 0,
+            start: Position::default(),
+            started: false,
+            done: false,
+        }
+    }
+
+    /// The element or array the last event is in, like `[0]/children[1]`
+    pub fn path(&self) -> &Kv2Path {
+        &self.path
+    }
+
+    pub(crate) fn start(&self) -> Position {
+        self.start
+    }
+
+    fn next_event(&mut self) -> Result<Option<Kv2Event>, ReadError> {
+        if !self.started {
+            self.started = true;
+            self.tokens.skip_bom()?;
+        }
+
+        // Comments before the next token come before its event
+        self.start = self.tokens.peek()?.1;
+        if let Some(comment) = self.tokens.take_comment() {
+            return Ok(Some(Kv2Event::Comment(comment)));
+        }
+
+        match self.stack.last_mut() {
+            None => self.root(),
+            Some(Frame::Element) => self.attribute().map(Some),
+            Some(Frame::Array { elements, items }) => {
+                let (elements, index) = (*elements, *items);
+                *items += 1;
+                self.array_item(elements, index).map(Some)
+            }
+        }
+    }
+
+    fn root(&mut self) -> Result<Option<Kv2Event>, ReadError> {
+        match self.tokens.next_token()? {
+            (Token::End, _) => Ok(None),
+            (Token::String(class), _) => {
+                self.path.push(PathSegment::Root(self.roots));
+                self.roots += 1;
+                self.expect(Token::OpenBrace, "{")?;
+                self.stack.push(Frame::Element);
+                Ok(Some(Kv2Event::StartElement { key: None, class }))
+            }
+            (_, at) => Err(self.error(at, &[Expected::QuotedString, Expected::EndOfInput])),
+        }
+    }
+
+    /// The next attribute of the current element, or its end
+    fn attribute(&mut self) -> Result<Kv2Event, ReadError> {
+        let key = match self.tokens.next_token()? {
+            (Token::CloseBrace, _) => return Ok(self.end()),
+            (Token::String(key), _) => key,
+            (_, at) => return Err(self.error(at, &[Expected::QuotedString, Expected::Token("}")])),
+        };
+
+        self.path.push(PathSegment::Attribute(key.clone()));
+        let data_type = self.expect_string()?;
+        let is_array = data_type.ends_with("_array");
+        match self.tokens.next_token()? {
+            (Token::String(raw), _) => {
+                self.path.pop();
+                Ok(Kv2Event::Attribute {
+                    key,
+                    data_type,
+                    raw,
+                })
+            }
+            (Token::OpenBrace, _) => {
+                self.stack.push(Frame::Element);
+                Ok(Kv2Event::StartElement {
+                    key: Some(key),
+                    class: data_type,
+                })
+            }
+            (Token::OpenBracket, _) if is_array => {
+                self.stack.push(Frame::Array {
+                    elements: data_type == "element_array",
+                    items: 0,
+                });
+                Ok(Kv2Event::StartArray { key, data_type })
+            }
+            (_, at) => {
+                let mut expected = vec![Expected::QuotedString, Expected::Token("{")];
+                if is_array {
+                    expected.push(Expected::Token("["));
+                }
+                Err(self.error(at, &expected))
+            }
+        }
+    }
+
+    /// The item at `index` of the current array, or its end
+    fn array_item(&mut self, elements: bool, index: usize) -> Result<Kv2Event, ReadError> {
+        if index == 0 {
+            if let (Token::CloseBracket, _) = self.tokens.peek()? {
+                self.tokens.next_token()?;
+                return Ok(self.end());
+            }
+        } else {
+            match self.tokens.next_token()? {
+                (Token::Comma, _) => {}
+                (Token::CloseBracket, _) => return Ok(self.end()),
+                (_, at) => {
+                    return Err(self.error(at, &[Expected::Token(","), Expected::Token("]")]))
+                }
+            }
+        }
+
+        self.path.push(PathSegment::Index(index));
+        let first = self.expect_string()?;
+        if !elements {
+            self.path.pop();
+            return Ok(Kv2Event::Item {
+                key: None,
+                raw: first,
+            });
+        }
+        match self.tokens.next_token()? {
+            (Token::OpenBrace, _) => {
+                self.stack.push(Frame::Element);
+                Ok(Kv2Event::StartElement {
+                    key: None,
+                    class: first,
+                })
+            }
+            (Token::String(raw), _) => {
+                self.path.pop();
+                Ok(Kv2Event::Item {
+                    key: Some(first),
+                    raw,
+                })
+            }
+            (_, at) => Err(self.error(at, &[Expected::QuotedString, Expected::Token("{")])),
+        }
+    }
+
+    /// Closes the current frame after its `}` or `]`
+    fn end(&mut self) -> Kv2Event {
+        self.path.pop();
+        match self.stack.pop() {
+            Some(Frame::Array { .. }) => Kv2Event::EndArray,
+            _ => Kv2Event::EndElement,
+        }
+    }
+
+    fn expect_string(&mut self) -> Result<String, ReadError> {
+        match self.tokens.next_token()? {
+            (Token::String(s), _) => Ok(s),
+            (_, at) => Err(self.error(at, &[Expected::QuotedString])),
+        }
+    }
+
+    fn expect(&mut self, token: Token, spelling: &'static str) -> Result<(), ReadError> {
+        match self.tokens.next_token()? {
+            (next, _) if next == token => Ok(()),
+            (_, at) => Err(self.error(at, &[Expected::Token(spelling)])),
+        }
+    }
+
+    fn error(&self, at: Position, expected: &[Expected]) -> ReadError {
+        ReadError::Parse(Kv2Error {
+            line: at.line,
+            column: at.column,
+            offset: at.offset,
+            expected: expected.to_vec(),
+            path: self.path.clone(),
+        })
+    }
+}
+
+impl<R: BufRead> Iterator for Kv2Events<R> {
+    type Item = Result<Kv2Event, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Comments read along with the last event
+        if let Some(comment) = self.tokens.take_comment() {
+            return Some(Ok(Kv2Event::Comment(comment)));
+        }
+        if self.done {
+            return None;
+        }
+        match self.next_event() {
+            Ok(Some(event)) => Some(Ok(event)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(error) => {
+                self.done = true;
+                // The tokenizer doesn't know where in the tree it is
+                Some(Err(match error {
+                    ReadError::Parse(mut error) if error.path.is_empty() => {
+                        error.path = self.path.clone();
+                        ReadError::Parse(error)
+                    }
+                    error => error,
+                }))
+            }
+        }
+    }
+}
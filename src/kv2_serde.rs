@@ -106,7 +106,7 @@ impl<'de> Deserializer<'de> for KV2Value {
             KV2Value::Bool(b) => visitor.visit_bool(b),
             KV2Value::Int(i) => visitor.visit_i64(i),
             KV2Value::Double(d) => visitor.visit_f64(d),
-            KV2Value::String(s) => visitor.visit_string(s),
+            KV2Value::String(s) | KV2Value::Element(s) => visitor.visit_string(s),
             KV2Value::Array(arr) => visitor.visit_seq(KV2ValueSeqAccess {
                 iter: arr.into_iter(),
             }),
@@ -193,6 +193,7 @@ impl<'de> Deserialize<'de> for KV2Value {
                 Ok(KV2Value::Object(KV2Object {
                     class_name: String::new(), // Class name might be empty here
                     fields,
+                    repeated: Vec::new(),
                 }))
             }
         }
@@ -24,29 +24,71 @@
 //!   }
 //! }
 //! ```
+pub mod borrowed;
+pub mod diagnostic;
+pub mod document;
+pub mod dot;
+pub mod element;
+pub mod encoding;
+pub mod error;
+pub mod events;
 #[cfg(feature = "serde")]
 pub mod kv2_serde;
+pub mod merge;
+pub mod query;
+pub mod reader;
+pub mod referrers;
+pub mod span;
+pub mod validate;
+pub mod visit;
+pub mod writer;
 
 mod test;
 
+pub use borrowed::{KV2ObjectRef, KV2ValueRef};
+pub use diagnostic::{Diagnostic, Severity};
+pub use document::{generate_element_id, DanglingReference, ElementId, Kv2Document, Kv2Header};
+pub use dot::DotOptions;
+pub use element::{Attribute, Element, ElementHandle, ElementRef};
+pub use encoding::{parse_kv2_bytes, parse_kv2_bytes_as, BytesError, DecodeError, Encoding};
+pub use error::{Expected, Kv2Error};
+pub use events::{Kv2Event, Kv2Events};
+pub use merge::MergePolicy;
+pub use query::{QueryError, Selection};
+pub use reader::{from_reader, from_reader_with, ReadError};
+pub use referrers::Referrer;
+pub use span::Span;
+pub use validate::{Location, ValidationIssue, ValidationIssueKind, ValidationRules};
+pub use visit::{
+    visit_object, visit_object_mut, visit_objects, visit_objects_mut, Kv2Path, Kv2Visitor,
+    Kv2VisitorMut, PathSegment, VisitedAttribute, Walk, WalkEntry,
+};
+pub use writer::{write_kv2, FloatSpelling, WriterOptions};
+
+use std::borrow::{Borrow, Cow};
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::hash::Hash;
 
-use log::info;
+use borrowed::{Borrowed, Owned, Tree};
+use log::{info, warn};
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_until},
-    character::complete::multispace1,
-    combinator::{map, opt},
+    bytes::complete::{tag, take_till, take_until},
+    character::complete::{multispace0, multispace1},
+    combinator::{cut, map, opt},
     multi::{many0, separated_list0},
-    sequence::delimited,
+    sequence::{delimited, preceded},
     IResult,
 };
+use span::{LineIndex, SourceMap};
 
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
 #[cfg(not(feature = "serde"))]
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum KV2Value {
     Bool(bool),
     Int(i64),
@@ -54,12 +96,14 @@ pub enum KV2Value {
     Vector(Vec<f64>),
     Quaternion(Vec<f64>),
     String(String),
+    /// Reference to another element by id, empty for a null reference
+    Element(String),
     Array(Vec<KV2Value>),
     Object(KV2Object),
 }
 
 #[cfg(feature = "serde")]
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub enum KV2Value {
     Bool(bool),
     Int(i64),
@@ -67,112 +111,911 @@ pub enum KV2Value {
     Vector(Vec<f64>),
     Quaternion(Vec<f64>),
     String(String),
+    /// Reference to another element by id, empty for a null reference
+    Element(String),
     Array(Vec<KV2Value>),
     Object(KV2Object),
 }
 
 #[cfg(feature = "serde")]
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct KV2Object {
     pub class_name: String,
     pub fields: HashMap<String, KV2Value>,
+    /// Later values of attributes that appear more than once, in source
+    /// order, only kept under [`DuplicateKeyPolicy::KeepAll`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub repeated: Vec<(String, KV2Value)>,
 }
 
 #[cfg(not(feature = "serde"))]
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct KV2Object {
     pub class_name: String,
     pub fields: HashMap<String, KV2Value>,
+    /// Later values of attributes that appear more than once, in source
+    /// order, only kept under [`DuplicateKeyPolicy::KeepAll`]
+    pub repeated: Vec<(String, KV2Value)>,
+}
+
+/// Optional syntax accepted by the parser and how it treats repeated
+/// attributes
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Accept `//` comments running to the end of the line wherever
+    /// whitespace is allowed, as in KeyValues1 files
+    pub line_comments: bool,
+    pub duplicate_keys: DuplicateKeyPolicy,
+}
+
+/// What to do with an attribute that appears more than once in an element
+///
+/// Every policy but `Error` logs a warning for each repeated attribute.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateKeyPolicy {
+    /// Fail at the repeated attribute. In recovery mode the error is
+    /// recorded and the first value kept.
+    Error,
+    KeepFirst,
+    #[default]
+    KeepLast,
+    /// Keep the first value in `fields` and the later ones in `repeated`
+    /// of [`KV2Object`], or of [`Element`] in documents
+    KeepAll,
+}
+
+impl DuplicateKeyPolicy {
+    fn warn_repeated(self, key: &str) {
+        let kept = match self {
+            DuplicateKeyPolicy::Error => "ignoring the repeated value",
+            DuplicateKeyPolicy::KeepFirst => "keeping the first value",
+            DuplicateKeyPolicy::KeepLast => "keeping the last value",
+            DuplicateKeyPolicy::KeepAll => "keeping every value",
+        };
+        warn!(
+            "Attribute {:?} appears more than once in the same element, {}",
+            key, kept
+        );
+    }
+}
+
+/// The attributes of one element, collected under a [`DuplicateKeyPolicy`]
+pub(crate) struct Fields<K, V> {
+    policy: DuplicateKeyPolicy,
+    fields: HashMap<K, V>,
+    /// Later values of repeated attributes under `KeepAll`, in order
+    repeated: Vec<(K, V)>,
+}
+
+impl<K: Hash + Eq + Clone + AsRef<str>, V> Fields<K, V> {
+    pub(crate) fn new(policy: DuplicateKeyPolicy) -> Fields<K, V> {
+        Fields {
+            policy,
+            fields: HashMap::new(),
+            repeated: Vec::new(),
+        }
+    }
+
+    pub(crate) fn contains(&self, key: &str) -> bool
+    where
+        K: Borrow<str>,
+    {
+        self.fields.contains_key(key)
+    }
+
+    /// Adds an attribute, returns `false` if it was already there
+    pub(crate) fn insert(&mut self, key: K, value: V) -> bool {
+        let mut entry = match self.fields.entry(key) {
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+                return true;
+            }
+            Entry::Occupied(entry) => entry,
+        };
+
+        self.policy.warn_repeated(entry.key().as_ref());
+        match self.policy {
+            DuplicateKeyPolicy::Error | DuplicateKeyPolicy::KeepFirst => {}
+            DuplicateKeyPolicy::KeepLast => {
+                entry.insert(value);
+            }
+            DuplicateKeyPolicy::KeepAll => {
+                let key = entry.key().clone();
+                self.repeated.push((key, value));
+            }
+        }
+        false
+    }
+
+    /// The collected attributes and the later values of repeated ones
+    pub(crate) fn finish(self) -> (HashMap<K, V>, Vec<(K, V)>) {
+        (self.fields, self.repeated)
+    }
+}
+
+/// A parsed attribute and the input it started at
+type Field<'a, V> = (&'a str, (Cow<'a, str>, V));
+
+/// A step of the path being parsed, borrowing attribute names from the
+/// input so it only becomes a [`Kv2Path`] when one is needed
+#[derive(Debug, Clone)]
+enum Segment<'a> {
+    Root(usize),
+    Attribute(Cow<'a, str>),
+    Repeated(Cow<'a, str>, usize),
+    Index(usize),
+}
+
+fn to_path(segments: &[Segment]) -> Kv2Path {
+    let mut path = Kv2Path::new();
+    for segment in segments {
+        path.push(match segment {
+            Segment::Root(i) => PathSegment::Root(*i),
+            Segment::Attribute(key) => PathSegment::Attribute(key.to_string()),
+            Segment::Repeated(key, occurrence) => {
+                PathSegment::Repeated(key.to_string(), *occurrence)
+            }
+            Segment::Index(i) => PathSegment::Index(*i),
+        });
+    }
+    path
+}
+
+/// The failure furthest into the input, its buffers are reused for the
+/// next one
+#[derive(Debug, Default)]
+struct Furthest<'a> {
+    offset: Option<usize>,
+    /// Everything that was expected there
+    expected: Vec<Expected>,
+    /// The path at the first of those failures
+    path: Vec<Segment<'a>>,
+}
+
+/// State shared by the parser functions while parsing one document
+struct ParseContext<'a> {
+    source: &'a str,
+    /// Attributes that appeared more than once in the same element
+    /// The element or attribute currently being parsed
+    path: RefCell<Vec<Segment<'a>>>,
+    furthest: RefCell<Furthest<'a>>,
+    /// How often each attribute appeared so far in the elements being
+    /// parsed, innermost last
+    occurrences: RefCell<Vec<HashMap<Cow<'a, str>, usize>>>,
+    /// Skip malformed attributes and elements instead of failing
+    recover: bool,
+    /// Errors skipped over in recovery mode
+    errors: RefCell<Vec<Kv2Error>>,
+    /// Position in the source of every root parsed, roots skipped in
+    /// recovery mode leave gaps
+    root_indices: RefCell<Vec<usize>>,
+    /// Spans of what was parsed, only recorded for documents
+    spans: Option<(LineIndex, RefCell<SourceMap>)>,
+    /// Byte offset just past the last token parsed
+    token_end: Cell<usize>,
+    line_comments: bool,
+    duplicate_keys: DuplicateKeyPolicy,
+}
+
+impl<'a> ParseContext<'a> {
+    fn new(source: &'a str) -> ParseContext<'a> {
+        ParseContext {
+            source,
+            path: RefCell::new(Vec::new()),
+            furthest: RefCell::new(Furthest::default()),
+            occurrences: RefCell::new(Vec::new()),
+            recover: false,
+            errors: RefCell::new(Vec::new()),
+            root_indices: RefCell::new(Vec::new()),
+            spans: None,
+            token_end: Cell::new(0),
+            line_comments: false,
+            duplicate_keys: DuplicateKeyPolicy::default(),
+        }
+    }
+
+    fn with_options(self, options: &ParseOptions) -> ParseContext<'a> {
+        ParseContext {
+            line_comments: options.line_comments,
+            duplicate_keys: options.duplicate_keys,
+            ..self
+        }
+    }
+
+    /// Records the span of every element, attribute and array item
+    fn with_spans(self) -> ParseContext<'a> {
+        let lines = LineIndex::new(self.source);
+        ParseContext {
+            spans: Some((lines, RefCell::new(SourceMap::default()))),
+            ..self
+        }
+    }
+
+    fn recovering(source: &'a str) -> ParseContext<'a> {
+        ParseContext {
+            recover: true,
+            ..ParseContext::new(source)
+        }
+    }
+
+    /// Runs `parse` with `segment` appended to the current path
+    fn within<T>(&self, segment: Segment<'a>, parse: impl FnOnce() -> T) -> T {
+        self.path.borrow_mut().push(segment);
+        let result = parse();
+        self.path.borrow_mut().pop();
+        result
+    }
+
+    fn current_path(&self) -> Kv2Path {
+        to_path(&self.path.borrow())
+    }
+
+    /// Runs `parse` on the body of an element, counting its attributes if
+    /// repeated ones are kept
+    fn within_element<T>(&self, parse: impl FnOnce() -> T) -> T {
+        if self.duplicate_keys != DuplicateKeyPolicy::KeepAll {
+            return parse();
+        }
+        self.occurrences.borrow_mut().push(HashMap::new());
+        let result = parse();
+        self.occurrences.borrow_mut().pop();
+        result
+    }
+
+    /// The path segment of the attribute `key` of the element being parsed,
+    /// later values of a repeated attribute get their own under
+    /// [`DuplicateKeyPolicy::KeepAll`] since the element keeps them all
+    fn attribute_segment(&self, key: &Cow<'a, str>) -> Segment<'a> {
+        let occurrences = self.occurrences.borrow();
+        let occurrence = occurrences
+            .last()
+            .and_then(|counts| counts.get(key).copied())
+            .unwrap_or(0);
+        if occurrence == 0 {
+            Segment::Attribute(key.clone())
+        } else {
+            Segment::Repeated(key.clone(), occurrence)
+        }
+    }
+
+    /// Counts an attribute of the element being parsed once it parsed
+    fn attribute_parsed(&self, key: Cow<'a, str>) {
+        if let Some(counts) = self.occurrences.borrow_mut().last_mut() {
+            *counts.entry(key).or_default() += 1;
+        }
+    }
+
+    /// Records that `expected` didn't match at `input`
+    fn fail(&self, input: &'a str, expected: Expected) {
+        let offset = self.offset(input);
+        let mut furthest = self.furthest.borrow_mut();
+        match furthest.offset {
+            Some(at) if at == offset => {
+                if !furthest.expected.contains(&expected) {
+                    furthest.expected.push(expected);
+                }
+            }
+            Some(at) if at > offset => {}
+            _ => {
+                furthest.offset = Some(offset);
+                furthest.expected.clear();
+                furthest.expected.push(expected);
+                furthest.path.clone_from(&self.path.borrow());
+            }
+        }
+    }
+
+    fn token(&self, token: &'static str) -> impl Fn(&'a str) -> IResult<&'a str, &'a str> + '_ {
+        move |input| {
+            tag(token)(input)
+                .inspect(|(rest, _)| self.token_end.set(self.offset(rest)))
+                .inspect_err(|_| {
+                    self.fail(input, Expected::Token(token));
+                })
+        }
+    }
+
+    fn quoted(&self) -> impl Fn(&'a str) -> IResult<&'a str, Cow<'a, str>> + '_ {
+        move |input| {
+            parse_quoted_string(input)
+                .inspect(|(rest, _)| self.token_end.set(self.offset(rest)))
+                .inspect_err(|_| {
+                    self.fail(input, Expected::QuotedString);
+                })
+        }
+    }
+
+    fn skip_comments_and_whitespace(&self, input: &'a str) -> IResult<&'a str, ()> {
+        skip_comments_and_whitespace(input, self.line_comments)
+    }
+
+    /// Runs `inner` between whitespace and comments
+    fn ws<O>(
+        &self,
+        inner: impl Fn(&'a str) -> IResult<&'a str, O>,
+    ) -> impl Fn(&'a str) -> IResult<&'a str, O> {
+        ws(inner, self.line_comments)
+    }
+
+    /// Turns the result of a parser into the crate's error type, pointing
+    /// at the furthest failure if it lies beyond where the parser gave up
+    fn finish<T>(&self, result: IResult<&'a str, T>) -> Result<(&'a str, T), Kv2Error> {
+        match result {
+            Ok(parsed) => Ok(parsed),
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(self.error(e.input)),
+            Err(nom::Err::Incomplete(_)) => Err(self.error(&self.source[self.source.len()..])),
+        }
+    }
+
+    /// Like [`Self::finish`], but input left over is an error too
+    fn finish_all<T>(&self, result: IResult<&'a str, T>) -> Result<T, Kv2Error> {
+        let (rest, parsed) = self.finish(result)?;
+        if !rest.is_empty() {
+            self.fail(rest, Expected::EndOfInput);
+            return Err(self.error(rest));
+        }
+        Ok(parsed)
+    }
+
+    fn error(&self, input: &'a str) -> Kv2Error {
+        let offset = self.offset(input);
+        let furthest = self.furthest.borrow();
+        match furthest.offset {
+            Some(at) if at >= offset => Kv2Error::new(
+                self.source,
+                at,
+                furthest.expected.clone(),
+                to_path(&furthest.path),
+            ),
+            _ => Kv2Error::new(self.source, offset, Vec::new(), Kv2Path::new()),
+        }
+    }
+
+    /// Records the error of something that failed to parse from `input`
+    /// on, returns the offset of the error
+    fn recover_from(&self, input: &'a str) -> usize {
+        let error = self.error(input);
+        let offset = error.offset;
+        self.errors.borrow_mut().push(error);
+        // Everything recorded so far was about the skipped input
+        self.furthest.borrow_mut().offset = None;
+        offset
+    }
+
+    /// Records that the element at the current path went from `start` up
+    /// to the last token
+    fn element_parsed(&self, start: &'a str) {
+        if let Some((lines, map)) = &self.spans {
+            let span = lines.span(self.source, self.offset(start)..self.token_end.get());
+            self.record_span(&mut map.borrow_mut().elements, self.current_path(), span);
+        }
+    }
+
+    /// Records that the attribute or array item at `path` went from `start`
+    /// up to the last token
+    fn value_parsed(&self, path: impl FnOnce() -> Kv2Path, start: &'a str) {
+        if let Some((lines, map)) = &self.spans {
+            let span = lines.span(self.source, self.offset(start)..self.token_end.get());
+            self.record_span(&mut map.borrow_mut().values, path(), span);
+        }
+    }
+
+    /// Spans of repeated attributes and what is in them follow the value
+    /// kept in the element's fields
+    fn record_span(&self, spans: &mut HashMap<Kv2Path, Span>, path: Kv2Path, span: Span) {
+        let keep_last = self.duplicate_keys == DuplicateKeyPolicy::KeepLast;
+        if keep_last || !spans.contains_key(&path) {
+            spans.insert(path, span);
+        }
+    }
+
+    /// Byte offset of `input` in the source, `input` has to be a suffix of it
+    fn offset(&self, input: &str) -> usize {
+        input.as_ptr() as usize - self.source.as_ptr() as usize
+    }
+
+    /// Collects the attributes of an element body into an object, handling
+    /// repeated ones according to the duplicate key policy
+    fn collect_fields<T: Tree<'a>>(
+        &self,
+        class_name: Cow<'a, str>,
+        kvs: Vec<Field<'a, T::Value>>,
+    ) -> Result<T::Object, nom::Err<nom::error::Error<&'a str>>> {
+        let mut fields = Fields::new(self.duplicate_keys);
+        for (input, (key, value)) in kvs {
+            if self.duplicate_keys == DuplicateKeyPolicy::Error && fields.contains(&key) {
+                let expected = vec![Expected::UniqueAttribute];
+                if self.recover {
+                    let path = self
+                        .current_path()
+                        .join(PathSegment::Attribute(key.to_string()));
+                    let error = Kv2Error::new(self.source, self.offset(input), expected, path);
+                    self.errors.borrow_mut().push(error);
+                    continue;
+                }
+                // Point the error at the repeated attribute, not at the
+                // end of the element where parsing got to
+                let mut path = self.path.borrow().clone();
+                path.push(Segment::Attribute(key));
+                *self.furthest.borrow_mut() = Furthest {
+                    offset: Some(self.offset(input)),
+                    expected,
+                    path,
+                };
+                return Err(nom::Err::Failure(nom::error::Error::new(
+                    input,
+                    nom::error::ErrorKind::Verify,
+                )));
+            }
+            fields.insert(T::key(key), value);
+        }
+        let (fields, repeated) = fields.finish();
+
+        Ok(T::object(class_name, fields, repeated))
+    }
 }
 
-pub fn parse_kv2(input: &str) -> IResult<&str, Vec<KV2Object>> {
+/// Parses root objects until the input ends or no further object can be
+/// parsed, returning the rest of the input with them
+///
+/// An element or array that was opened but can't be parsed to its end is an
+/// error.
+pub fn parse_kv2(input: &str) -> Result<(&str, Vec<KV2Object>), Kv2Error> {
+    let context = ParseContext::new(input);
+    context.finish(parse_roots::<Owned>(input, &context))
+}
+
+/// Parses root objects like [`parse_kv2`], accepting the syntax enabled in
+/// `options`
+pub fn parse_kv2_with<'a>(
+    input: &'a str,
+    options: &ParseOptions,
+) -> Result<(&'a str, Vec<KV2Object>), Kv2Error> {
+    let context = ParseContext::new(input).with_options(options);
+    context.finish(parse_roots::<Owned>(input, &context))
+}
+
+/// Parses root objects like [`parse_kv2`], borrowing keys, class names and
+/// strings from `input` instead of copying them
+pub fn parse_kv2_borrowed(input: &str) -> Result<(&str, Vec<KV2ObjectRef<'_>>), Kv2Error> {
+    let context = ParseContext::new(input);
+    context.finish(parse_roots::<Borrowed>(input, &context))
+}
+
+/// Parses root objects like [`parse_kv2`], failing if input is left over
+pub(crate) fn parse_kv2_complete(input: &str) -> Result<Vec<KV2Object>, Kv2Error> {
+    let context = ParseContext::new(input);
+    context.finish_all(parse_roots::<Owned>(input, &context))
+}
+
+fn parse_roots<'a, T: Tree<'a>>(
+    input: &'a str,
+    context: &ParseContext<'a>,
+) -> IResult<&'a str, Vec<T::Object>> {
     info!("Parsing KV2 document...");
 
-    let (input, _) = skip_comments_and_whitespace(input)?;
+    let (input, _) = context.skip_comments_and_whitespace(input)?;
 
     // Parse optional XML-style comment at the top
     let (input, _) = opt(parse_comment)(input)?;
 
     // Parse multiple root objects
-    let (input, objects) = many0(ws(parse_root_object))(input)?;
+    let count = Cell::new(0);
+    let parse_root = |i| {
+        let result = context.within(Segment::Root(count.get()), || {
+            parse_root_object::<T>(i, context)
+        });
+        if result.is_ok() {
+            context.root_indices.borrow_mut().push(count.get());
+        }
+        count.set(count.get() + 1);
+        result
+    };
+
+    if context.recover {
+        let mut input = input;
+        let mut objects = Vec::new();
+        loop {
+            let (i, _) = context.skip_comments_and_whitespace(input)?;
+            if i.is_empty() {
+                return Ok((i, objects));
+            }
+            match parse_root(i) {
+                Ok((rest, object)) => {
+                    objects.push(object);
+                    input = rest;
+                }
+                Err(nom::Err::Incomplete(needed)) => return Err(nom::Err::Incomplete(needed)),
+                Err(_) => {
+                    let offset = context.recover_from(i);
+                    input = resync(i, offset - context.offset(i), false, context.line_comments);
+                }
+            }
+        }
+    }
 
+    let (input, objects) = many0(context.ws(parse_root))(input)?;
     Ok((input, objects))
 }
 
-fn parse_root_object(input: &str) -> IResult<&str, KV2Object> {
+/// Parses a document like [`parse_kv2`], also reading the DMX header comment
+/// and indexing every element by its id
+pub fn parse_kv2_document(input: &str) -> Result<(&str, Kv2Document), Kv2Error> {
+    info!("Parsing KV2 document with header...");
+
+    let context = ParseContext::new(input).with_spans();
+    let (rest, (header, objects)) = context.finish(parse_header_and_roots(input, &context))?;
+
+    Ok((rest, document_from(header, objects, &context)))
+}
+
+/// Parses a whole document like [`parse_kv2_document`], failing if any
+/// input is left after the last root element
+///
+/// The error points at where parsing stopped, which for a syntax error in
+/// a root element is inside that element.
+pub fn parse_document(input: &str) -> Result<Kv2Document, Kv2Error> {
+    parse_document_with(input, &ParseOptions::default())
+}
+
+/// Parses a whole document like [`parse_document`], accepting the syntax
+/// enabled in `options`
+pub fn parse_document_with(input: &str, options: &ParseOptions) -> Result<Kv2Document, Kv2Error> {
+    info!("Parsing complete KV2 document...");
+
+    let context = ParseContext::new(input).with_options(options).with_spans();
+    let (header, objects) = context.finish_all(parse_header_and_roots(input, &context))?;
+
+    Ok(document_from(header, objects, &context))
+}
+
+/// Parses a document like [`parse_kv2_document`], but instead of stopping
+/// at the first malformed attribute or element it records the error, skips
+/// to the next attribute or `}` and carries on
+///
+/// Returns the partial document with everything that could be parsed and
+/// every error in the order they were found, the whole input is read.
+pub fn parse_kv2_document_recovering(input: &str) -> (Kv2Document, Vec<Kv2Error>) {
+    parse_kv2_document_recovering_with(input, &ParseOptions::default())
+}
+
+/// Parses a document like [`parse_kv2_document_recovering`], accepting the
+/// syntax enabled in `options`
+pub fn parse_kv2_document_recovering_with(
+    input: &str,
+    options: &ParseOptions,
+) -> (Kv2Document, Vec<Kv2Error>) {
+    info!("Parsing KV2 document in recovery mode...");
+
+    let context = ParseContext::recovering(input)
+        .with_options(options)
+        .with_spans();
+    let result = context.finish(parse_header_and_roots(input, &context));
+    let mut errors = context.errors.take();
+    let document = match result {
+        Ok((_, (header, objects))) => document_from(header, objects, &context),
+        Err(error) => {
+            errors.push(error);
+            Kv2Document::default()
+        }
+    };
+    (document, errors)
+}
+
+fn document_from(
+    header: Option<Kv2Header>,
+    objects: Vec<KV2Object>,
+    context: &ParseContext<'_>,
+) -> Kv2Document {
+    let spans = context
+        .spans
+        .as_ref()
+        .map(|(_, map)| map.take())
+        .unwrap_or_default();
+    let roots = context
+        .root_indices
+        .take()
+        .into_iter()
+        .zip(objects)
+        .collect();
+    Kv2Document::with_source_map(header, roots, spans)
+}
+
+fn parse_header_and_roots<'a>(
+    input: &'a str,
+    context: &ParseContext<'a>,
+) -> IResult<&'a str, (Option<Kv2Header>, Vec<KV2Object>)> {
+    // `<!-- -->` comments are left alone, the first one may be the header
+    let (input, _) = skip_whitespace(input, context.line_comments)?;
+    let (input, header) = opt(parse_header)(input)?;
+    let (input, objects) = parse_roots::<Owned>(input, context)?;
+    Ok((input, (header, objects)))
+}
+
+fn parse_header(input: &str) -> IResult<&str, Kv2Header> {
+    let (remaining, comment) = delimited(tag("<!--"), take_until("-->"), tag("-->"))(input)?;
+    match Kv2Header::from_comment(comment) {
+        Some(header) => Ok((remaining, header)),
+        None => Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Tag,
+        ))),
+    }
+}
+
+fn parse_root_object<'a, T: Tree<'a>>(
+    input: &'a str,
+    context: &ParseContext<'a>,
+) -> IResult<&'a str, T::Object> {
     info!("Parsing KV2 root object...");
 
-    let (input, _) = skip_comments_and_whitespace(input)?;
+    let (start, _) = context.skip_comments_and_whitespace(input)?;
 
     // Parse the root class name
-    let (input, class_name) = ws(parse_quoted_string)(input)?;
+    let (input, class_name) = context.ws(context.quoted())(start)?;
 
     // Parse the object body
-    let (input, fields) = parse_object_body(input)?;
+    let (input, object) = parse_object_body::<T>(input, class_name, context)?;
+
+    context.element_parsed(start);
+    Ok((input, object))
+}
+
+fn parse_object_body<'a, T: Tree<'a>>(
+    input: &'a str,
+    class_name: Cow<'a, str>,
+    context: &ParseContext<'a>,
+) -> IResult<&'a str, T::Object> {
+    let (input, _) = context.ws(context.token("{"))(input)?;
+    if context.recover {
+        let (input, kvs) =
+            context.within_element(|| parse_fields_recovering::<T>(input, context))?;
+        return Ok((input, context.collect_fields::<T>(class_name, kvs)?));
+    }
+
+    let (input, kvs) = context.within_element(|| {
+        many0(|i| {
+            // Remember where each attribute starts to report repeated ones
+            let (i, _) = context.skip_comments_and_whitespace(i)?;
+            let (rest, kv) = context.ws(|i| parse_key_value_or_entry::<T>(i, context))(i)?;
+            Ok((rest, (i, kv)))
+        })(input)
+    })?;
+    // An opened element has to be closed, don't let callers backtrack
+    let (input, _) = cut(context.ws(context.token("}")))(input)?;
+    Ok((input, context.collect_fields::<T>(class_name, kvs)?))
+}
+
+/// Parses attributes up to and including the closing `}`, skipping to the
+/// next attribute after a malformed one. A missing `}` at the end of the
+/// input is recorded as an error too.
+fn parse_fields_recovering<'a, T: Tree<'a>>(
+    mut input: &'a str,
+    context: &ParseContext<'a>,
+) -> IResult<&'a str, Vec<Field<'a, T::Value>>> {
+    let mut kvs = Vec::new();
+    loop {
+        let (i, _) = context.skip_comments_and_whitespace(input)?;
+        if let Ok((rest, _)) = context.ws(context.token("}"))(i) {
+            return Ok((rest, kvs));
+        }
+        if i.is_empty() {
+            context.recover_from(i);
+            return Ok((i, kvs));
+        }
 
-    Ok((input, KV2Object { class_name, fields }))
+        match context.ws(|i| parse_key_value_or_entry::<T>(i, context))(i) {
+            Ok((rest, kv)) => {
+                kvs.push((i, kv));
+                input = rest;
+            }
+            Err(nom::Err::Incomplete(needed)) => return Err(nom::Err::Incomplete(needed)),
+            Err(_) => {
+                let offset = context.recover_from(i);
+                input = resync(i, offset - context.offset(i), true, context.line_comments);
+            }
+        }
+    }
 }
 
-fn parse_object_body(input: &str) -> IResult<&str, HashMap<String, KV2Value>> {
-    let (input, _) = ws(tag("{"))(input)?;
-    let (input, kvs) = many0(ws(parse_key_value_or_entry))(input)?;
-    let (input, _) = ws(tag("}"))(input)?;
-    Ok((input, kvs.into_iter().collect()))
+/// Skips a malformed attribute or root element starting at `input`, whose
+/// error was `error_offset` bytes in
+///
+/// Stops at the next line starting with a quoted string, or at a `}`
+/// closing the enclosing element if `stop_at_close` is set, but not inside
+/// braces or brackets opened by the skipped part. `//` comments are skipped
+/// if `line_comments` is set.
+fn resync(input: &str, error_offset: usize, stop_at_close: bool, line_comments: bool) -> &str {
+    let bytes = input.as_bytes();
+    let mut depth = 0usize;
+    let mut line_start = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let at_stop = (c == b'}' && stop_at_close) || (c == b'"' && line_start);
+        if at_stop && depth == 0 && i >= error_offset {
+            return &input[i..];
+        }
+        match c {
+            b'\n' => line_start = true,
+            b' ' | b'\t' | b'\r' => {}
+            b'"' => {
+                // Skip the string, an unterminated one ends with the line
+                line_start = false;
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    match bytes[i] {
+                        b'"' => {
+                            i += 1;
+                            break;
+                        }
+                        b'\\' => i += 2,
+                        _ => i += 1,
+                    }
+                }
+                continue;
+            }
+            b'/' if line_comments && bytes.get(i + 1) == Some(&b'/') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            b'{' | b'[' => {
+                depth += 1;
+                line_start = false;
+            }
+            b'}' | b']' => {
+                depth = depth.saturating_sub(1);
+                line_start = false;
+            }
+            _ => line_start = false,
+        }
+        i += 1;
+    }
+    &input[input.len()..]
 }
 
-fn parse_key_value_or_entry(input: &str) -> IResult<&str, (String, KV2Value)> {
+fn parse_key_value_or_entry<'a, T: Tree<'a>>(
+    input: &'a str,
+    context: &ParseContext<'a>,
+) -> IResult<&'a str, (Cow<'a, str>, T::Value)> {
+    let (start, _) = context.skip_comments_and_whitespace(input)?;
     // Try to parse a key-value pair first, then an array, then an object
-    alt((
-        parse_key_value,
-        parse_array,
-        parse_object_with_classname_as_value,
-    ))(input)
+    let (input, (key, value)) = alt((
+        |i| parse_key_value::<T>(i, context),
+        |i| parse_array::<T>(i, context),
+        |i| parse_object_with_classname_as_value::<T>(i, context),
+    ))(start)?;
+
+    let segment = context.attribute_segment(&key);
+    let path = || context.within(segment, || context.current_path());
+    context.value_parsed(path, start);
+    context.attribute_parsed(key.clone());
+    Ok((input, (key, value)))
 }
 
-fn parse_key_value(input: &str) -> IResult<&str, (String, KV2Value)> {
+fn parse_key_value<'a, T: Tree<'a>>(
+    input: &'a str,
+    context: &ParseContext<'a>,
+) -> IResult<&'a str, (Cow<'a, str>, T::Value)> {
     info!("Parsing key-value pair...");
 
-    let (input, key) = ws(parse_quoted_string)(input)?;
-    let (input, data_type) = ws(parse_quoted_string)(input)?;
-    let (input, value_str) = ws(parse_quoted_string)(input)?;
-
-    let value = match data_type.as_str() {
-        "bool" => KV2Value::Bool(value_str == "1" || value_str.to_lowercase() == "true"),
-        "int" | "int32" | "int64" => KV2Value::Int(value_str.parse::<i64>().unwrap_or(0)),
-        "float" => KV2Value::Double(value_str.parse::<f64>().unwrap_or(0.0)),
-        "string" => KV2Value::String(value_str),
-        "elementid" => KV2Value::String(value_str), // Treat element IDs as strings
-        "vector3" => {
+    let (input, key) = context.ws(context.quoted())(input)?;
+    let (input, (data_type, value_str)) =
+        context.within(context.attribute_segment(&key), || {
+            let (input, data_type) = context.ws(context.quoted())(input)?;
+            let (input, value_str) = context.ws(context.quoted())(input)?;
+            Ok((input, (data_type, value_str)))
+        })?;
+
+    let value = T::value(typed_value(&data_type, value_str));
+    Ok((input, (key, value)))
+}
+
+/// Converts the value of a `"key" "type" "value"` attribute
+pub(crate) fn typed_value<'a>(data_type: &str, value_str: Cow<'a, str>) -> KV2ValueRef<'a> {
+    match data_type {
+        "bool" => KV2ValueRef::Bool(value_str == "1" || value_str.eq_ignore_ascii_case("true")),
+        "int" | "int32" | "int64" => KV2ValueRef::Int(value_str.parse::<i64>().unwrap_or(0)),
+        "float" => KV2ValueRef::Double(parse_float(&value_str).unwrap_or(0.0)),
+        "string" => KV2ValueRef::String(value_str),
+        "elementid" => KV2ValueRef::String(value_str), // Treat element IDs as strings
+        "element" => KV2ValueRef::Element(value_str),
+        "vector2" | "vector3" | "vector4" | "qangle" | "color" => {
             // Parse the vector string into a Vec<f64>
-            match parse_vector(value_str.as_str()) {
-                Ok(vector) => KV2Value::Vector(vector),
-                Err(_) => KV2Value::String(value_str), // Fallback to string if parsing fails
+            match parse_vector(&value_str) {
+                Ok(vector) => KV2ValueRef::Vector(vector),
+                Err(_) => KV2ValueRef::String(value_str), // Fallback to string if parsing fails
             }
         }
         "quaternion" => {
-            match parse_quaternion(value_str.as_str()) {
-                Ok(vector) => KV2Value::Quaternion(vector),
-                Err(_) => KV2Value::String(value_str), // Fallback to string if parsing fails
+            match parse_quaternion(&value_str) {
+                Ok(vector) => KV2ValueRef::Quaternion(vector),
+                Err(_) => KV2ValueRef::String(value_str), // Fallback to string if parsing fails
             }
         }
         // Handle other data types as needed
-        _ => KV2Value::String(value_str), // Default to string
-    };
-
-    Ok((input, (key, value)))
+        _ => KV2ValueRef::String(value_str), // Default to string
+    }
 }
+
 fn parse_vector(input: &str) -> Result<Vec<f64>, std::num::ParseFloatError> {
-    input.split_whitespace().map(|s| s.parse::<f64>()).collect()
+    input.split_whitespace().map(parse_float).collect()
 }
 
 fn parse_quaternion(input: &str) -> Result<Vec<f64>, std::num::ParseFloatError> {
-    input.split_whitespace().map(|s| s.parse::<f64>()).collect()
+    input.split_whitespace().map(parse_float).collect()
+}
+
+/// Parses a float, also accepting the MSVC spellings of special values
+/// (`1.#INF`, `-1.#IND`, `1.#QNAN`, ...) written by Valve's Windows tools.
+fn parse_float(input: &str) -> Result<f64, std::num::ParseFloatError> {
+    match input.parse::<f64>() {
+        Ok(value) => Ok(value),
+        Err(e) => parse_msvc_special_float(input).ok_or(e),
+    }
+}
+
+fn parse_msvc_special_float(input: &str) -> Option<f64> {
+    let (negative, rest) = match input.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, input.strip_prefix('+').unwrap_or(input)),
+    };
+
+    // MSVC prints the mantissa as "1" followed by "#" and the kind, rounded
+    // or padded with zeros to the requested precision ("1.#INF00", "1.#J")
+    let digits = rest
+        .strip_prefix("1.")
+        .filter(|digits| !digits.is_empty())?;
+    let value = [
+        ("#INF", f64::INFINITY),
+        ("#IND", f64::NAN),
+        ("#QNAN", f64::NAN),
+        ("#SNAN", f64::NAN),
+    ]
+    .into_iter()
+    .find(|(spelling, _)| msvc_rounded(spelling, digits.len()) == digits)?
+    .1;
+
+    Some(if negative { -value } else { value })
+}
+
+/// `spelling` as MSVC prints it with `precision` digits: cut off and rounded
+/// up by bumping the last character when the next one is `5` or more, which
+/// every letter is
+///
+/// `#INF` and `#IND` round the same way up to three digits (`1.#J`,
+/// `1.#IO`), those are read as infinities.
+fn msvc_rounded(spelling: &str, precision: usize) -> String {
+    if precision >= spelling.len() {
+        return format!("{:0<width$}", spelling, width = precision);
+    }
+    let mut rounded = spelling.as_bytes()[..precision].to_vec();
+    if let (Some(last), true) = (rounded.last_mut(), spelling.as_bytes()[precision] >= b'5') {
+        *last += 1;
+    }
+    String::from_utf8(rounded).unwrap_or_default()
 }
 
-fn parse_array(input: &str) -> IResult<&str, (String, KV2Value)> {
+fn parse_array<'a, T: Tree<'a>>(
+    input: &'a str,
+    context: &ParseContext<'a>,
+) -> IResult<&'a str, (Cow<'a, str>, T::Value)> {
     info!("Parsing array...");
-    let (input, key) = ws(parse_quoted_string)(input)?;
-    let (input, data_type) = ws(parse_quoted_string)(input)?;
+    let (input, key) = context.ws(context.quoted())(input)?;
+    let (input, elements) = context.within(context.attribute_segment(&key), || {
+        parse_array_body::<T>(input, context)
+    })?;
+
+    Ok((input, (key, T::array(elements))))
+}
+
+fn parse_array_body<'a, T: Tree<'a>>(
+    input: &'a str,
+    context: &ParseContext<'a>,
+) -> IResult<&'a str, Vec<T::Value>> {
+    let (input, data_type) = context.ws(context.quoted())(input)?;
 
     // Check if data_type ends with "_array"
     if !data_type.ends_with("_array") {
@@ -185,110 +1028,194 @@ fn parse_array(input: &str) -> IResult<&str, (String, KV2Value)> {
     // Extract the base data type (e.g., "vector3" from "vector3_array")
     let base_data_type = &data_type[..data_type.len() - "_array".len()];
 
-    let (input, _) = ws(tag("["))(input)?;
+    let (input, _) = context.ws(context.token("["))(input)?;
     // Handle commas between elements and parse elements based on base_data_type
-    let (input, elements) =
-        separated_list0(ws(tag(",")), |i| parse_array_element(i, base_data_type))(input)?;
-    let (input, _) = ws(tag("]"))(input)?;
+    let count = Cell::new(0);
+    let (input, elements) = separated_list0(context.ws(context.token(",")), |i| {
+        let result = context.within(Segment::Index(count.get()), || {
+            let (start, _) = context.skip_comments_and_whitespace(i)?;
+            let (rest, item) = parse_array_element::<T>(start, base_data_type, context)?;
+            context.value_parsed(|| context.current_path(), start);
+            Ok((rest, item))
+        });
+        count.set(count.get() + 1);
+        result
+    })(input)?;
+    let (input, _) = cut(context.ws(context.token("]")))(input)?;
 
-    Ok((input, (key, KV2Value::Array(elements))))
+    Ok((input, elements))
 }
 
-fn parse_array_element<'a>(input: &'a str, base_data_type: &str) -> IResult<&'a str, KV2Value> {
+fn parse_array_element<'a, T: Tree<'a>>(
+    input: &'a str,
+    base_data_type: &str,
+    context: &ParseContext<'a>,
+) -> IResult<&'a str, T::Value> {
     match base_data_type {
         "element" => {
             // Elements can be objects or key-value pairs
-            alt((parse_element, parse_array_key_value))(input)
+            alt((
+                |i| parse_element::<T>(i, context),
+                |i| parse_array_key_value::<T>(i, context),
+            ))(input)
         }
         _ => {
             // For other types, parse the element value according to the base data type
-            parse_array_value(input, base_data_type)
+            parse_array_value::<T>(input, base_data_type, context)
         }
     }
 }
 
-fn parse_array_value<'a>(input: &'a str, data_type: &str) -> IResult<&'a str, KV2Value> {
+fn parse_array_value<'a, T: Tree<'a>>(
+    input: &'a str,
+    data_type: &str,
+    context: &ParseContext<'a>,
+) -> IResult<&'a str, T::Value> {
     info!("Parsing array value of type {}", data_type);
 
     // Parse the value as a quoted string
-    let (input, value_str) = ws(parse_quoted_string)(input)?;
-
-    let value = match data_type {
-        "bool" => KV2Value::Bool(value_str == "1" || value_str.to_lowercase() == "true"),
-        "int" | "int32" | "int64" => KV2Value::Int(value_str.parse::<i64>().unwrap_or(0)),
-        "float" => KV2Value::Double(value_str.parse::<f64>().unwrap_or(0.0)),
-        "string" => KV2Value::String(value_str),
-        "vector3" => {
+    let (input, value_str) = context.ws(context.quoted())(input)?;
+
+    Ok((input, T::value(typed_array_value(data_type, value_str))))
+}
+
+/// Converts an item of a `type_array` attribute
+pub(crate) fn typed_array_value<'a>(data_type: &str, value_str: Cow<'a, str>) -> KV2ValueRef<'a> {
+    match data_type {
+        "bool" => KV2ValueRef::Bool(value_str == "1" || value_str.eq_ignore_ascii_case("true")),
+        "int" | "int32" | "int64" => KV2ValueRef::Int(value_str.parse::<i64>().unwrap_or(0)),
+        "float" => KV2ValueRef::Double(parse_float(&value_str).unwrap_or(0.0)),
+        "string" => KV2ValueRef::String(value_str),
+        "vector2" | "vector3" | "vector4" | "qangle" | "color" => {
             match parse_vector(&value_str) {
-                Ok(vector) => KV2Value::Vector(vector),
-                Err(_) => KV2Value::String(value_str), // Fallback to string
+                Ok(vector) => KV2ValueRef::Vector(vector),
+                Err(_) => KV2ValueRef::String(value_str), // Fallback to string
             }
         }
         "quaternion" => {
-            match parse_quaternion(value_str.as_str()) {
-                Ok(vector) => KV2Value::Quaternion(vector),
-                Err(_) => KV2Value::String(value_str), // Fallback to string if parsing fails
+            match parse_quaternion(&value_str) {
+                Ok(vector) => KV2ValueRef::Quaternion(vector),
+                Err(_) => KV2ValueRef::String(value_str), // Fallback to string if parsing fails
             }
         }
         // Add more data types as needed
-        _ => KV2Value::String(value_str), // Default to string
-    };
-
-    Ok((input, value))
+        _ => KV2ValueRef::String(value_str), // Default to string
+    }
 }
 
-fn parse_array_key_value(input: &str) -> IResult<&str, KV2Value> {
+fn parse_array_key_value<'a, T: Tree<'a>>(
+    input: &'a str,
+    context: &ParseContext<'a>,
+) -> IResult<&'a str, T::Value> {
     info!("Parsing array key-value pair...");
 
-    let (input, key) = ws(parse_quoted_string)(input)?;
-    let (input, value) = ws(parse_quoted_string)(input)?;
+    let (input, key) = context.ws(context.quoted())(input)?;
+    let (input, value) = context.ws(context.quoted())(input)?;
+
+    Ok((input, T::value(array_key_value(key, value))))
+}
+
+/// Converts a `"key" "value"` item of an `element_array`
+pub(crate) fn array_key_value<'a>(key: Cow<'a, str>, value: Cow<'a, str>) -> KV2ValueRef<'a> {
+    // "element" "<id>" refers to an element defined elsewhere in the document
+    if key == "element" {
+        return KV2ValueRef::Element(value);
+    }
 
     // Represent the key-value pair as an object with a single field
     let mut fields = HashMap::new();
-    fields.insert(key, KV2Value::String(value));
+    fields.insert(key, KV2ValueRef::String(value));
 
-    Ok((
-        input,
-        KV2Value::Object(KV2Object {
-            class_name: String::new(), // No class name
-            fields,
-        }),
-    ))
+    KV2ValueRef::Object(KV2ObjectRef {
+        class_name: Cow::Borrowed(""), // No class name
+        fields,
+        repeated: Vec::new(),
+    })
 }
 
-fn parse_element(input: &str) -> IResult<&str, KV2Value> {
+fn parse_element<'a, T: Tree<'a>>(
+    input: &'a str,
+    context: &ParseContext<'a>,
+) -> IResult<&'a str, T::Value> {
     info!("Parsing element...");
+    let (start, _) = context.skip_comments_and_whitespace(input)?;
     // Parse the class name
-    let (input, class_name) = ws(parse_quoted_string)(input)?;
+    let (input, class_name) = context.ws(context.quoted())(start)?;
     // Parse the object body
-    let (input, fields) = parse_object_body(input)?;
-    Ok((input, KV2Value::Object(KV2Object { class_name, fields })))
+    let (input, object) = parse_object_body::<T>(input, class_name, context)?;
+    context.element_parsed(start);
+    Ok((input, T::object_value(object)))
 }
 
-fn parse_object_with_classname_as_value(input: &str) -> IResult<&str, (String, KV2Value)> {
+fn parse_object_with_classname_as_value<'a, T: Tree<'a>>(
+    input: &'a str,
+    context: &ParseContext<'a>,
+) -> IResult<&'a str, (Cow<'a, str>, T::Value)> {
     info!("Parsing object with classname...");
     // Parse the key
-    let (input, key) = ws(parse_quoted_string)(input)?;
-    // Parse the data type (should be the class name)
-    let (input, data_type) = ws(parse_quoted_string)(input)?;
-    // Parse the object body
-    let (input, fields) = parse_object_body(input)?;
-    Ok((
-        input,
-        (
-            key,
-            KV2Value::Object(KV2Object {
-                class_name: data_type,
-                fields,
-            }),
-        ),
-    ))
+    let (input, key) = context.ws(context.quoted())(input)?;
+    let (input, object) = context.within(context.attribute_segment(&key), || {
+        let (start, _) = context.skip_comments_and_whitespace(input)?;
+        // Parse the data type (should be the class name)
+        let (input, data_type) = context.ws(context.quoted())(start)?;
+        // Parse the object body
+        let (input, object) = parse_object_body::<T>(input, data_type, context)?;
+        context.element_parsed(start);
+        Ok((input, object))
+    })?;
+    Ok((input, (key, T::object_value(object))))
 }
 
-fn parse_quoted_string(input: &str) -> IResult<&str, String> {
+/// Parses a string in double quotes, unescaping `\"`, `\\`, `\n` and `\t`
+///
+/// Other backslashes are kept as they are, so unescaped Windows paths like
+/// `materials\models` still read correctly. The string is borrowed from the
+/// input unless it had escapes.
+fn parse_quoted_string(input: &str) -> IResult<&str, Cow<'_, str>> {
     info!("Parsing quoted string...");
-    let result = delimited(tag("\""), take_until("\""), tag("\""))(input);
-    result.map(|(remaining, s)| (remaining, s.to_string()))
+    let (body, _) = tag("\"")(input)?;
+
+    // Only allocated once the first escape is found
+    let mut unescaped: Option<String> = None;
+    let mut chars = body.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                let value = match unescaped {
+                    Some(value) => Cow::Owned(value),
+                    None => Cow::Borrowed(&body[..i]),
+                };
+                return Ok((&body[i + 1..], value));
+            }
+            '\\' => match chars.clone().next() {
+                Some((_, escaped @ ('"' | '\\' | 'n' | 't'))) => {
+                    chars.next();
+                    unescaped
+                        .get_or_insert_with(|| body[..i].to_string())
+                        .push(match escaped {
+                            'n' => '\n',
+                            't' => '\t',
+                            other => other,
+                        });
+                }
+                _ => {
+                    if let Some(value) = &mut unescaped {
+                        value.push('\\');
+                    }
+                }
+            },
+            c => {
+                if let Some(value) = &mut unescaped {
+                    value.push(c);
+                }
+            }
+        }
+    }
+
+    Err(nom::Err::Error(nom::error::Error::new(
+        input,
+        nom::error::ErrorKind::TakeUntil,
+    )))
 }
 
 fn parse_comment(input: &str) -> IResult<&str, ()> {
@@ -301,21 +1228,49 @@ fn parse_comment(input: &str) -> IResult<&str, ()> {
     xml_style(input)
 }
 
-fn skip_comments_and_whitespace(input: &str) -> IResult<&str, ()> {
+fn parse_line_comment(input: &str) -> IResult<&str, ()> {
+    // Parse C-style line comments (// ...) up to the end of the line
+    map(preceded(tag("//"), take_till(|c| c == '\n')), |_| ())(input)
+}
+
+/// Skips whitespace, and `//` comments if `line_comments` is set
+fn skip_whitespace(input: &str, line_comments: bool) -> IResult<&str, ()> {
+    if !line_comments {
+        return map(multispace0, |_| ())(input);
+    }
+    map(
+        many0(alt((map(multispace1, |_| ()), parse_line_comment))),
+        |_| (),
+    )(input)
+}
+
+/// Skips whitespace and `<!-- -->` comments, and `//` comments if
+/// `line_comments` is set
+fn skip_comments_and_whitespace(input: &str, line_comments: bool) -> IResult<&str, ()> {
+    let line_comment = |i| {
+        if line_comments {
+            parse_line_comment(i)
+        } else {
+            Err(nom::Err::Error(nom::error::Error::new(
+                i,
+                nom::error::ErrorKind::Tag,
+            )))
+        }
+    };
     map(
-        many0(alt((map(multispace1, |_| ()), parse_comment))),
+        many0(alt((map(multispace1, |_| ()), parse_comment, line_comment))),
         |_| (),
     )(input)
 }
 
-fn ws<'a, F, O>(inner: F) -> impl Fn(&'a str) -> IResult<&'a str, O>
+fn ws<'a, F, O>(inner: F, line_comments: bool) -> impl Fn(&'a str) -> IResult<&'a str, O>
 where
-    F: 'a + Fn(&'a str) -> IResult<&'a str, O>,
+    F: Fn(&'a str) -> IResult<&'a str, O>,
 {
     move |input: &str| {
-        let (input, _) = skip_comments_and_whitespace(input)?;
+        let (input, _) = skip_comments_and_whitespace(input, line_comments)?;
         let (input, res) = inner(input)?;
-        let (input, _) = skip_comments_and_whitespace(input)?;
+        let (input, _) = skip_comments_and_whitespace(input, line_comments)?;
         Ok((input, res))
     }
 }
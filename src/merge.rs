@@ -0,0 +1,182 @@
+//! Importing elements from one document into another
+//!
+//! # Example
+//! ```rust
+//! use kv2::{parse_kv2_document, MergePolicy};
+//!
+//! let (_, mut doc) = parse_kv2_document(r#"
+//! "DmElement"
+//! {
+//! "id" "elementid" "df939bf4-8dd6-435c-9eef-a6e25434ecca"
+//! "particleSystemDefinitions" "element_array"
+//! [
+//! ]
+//! }
+//! "#).unwrap();
+//! let (_, other) = parse_kv2_document(r#"
+//! "DmeParticleSystemDefinition"
+//! {
+//! "id" "elementid" "3535d7f5-7d31-4b97-b772-46fadd300992"
+//! "name" "string" "default"
+//! }
+//! "#).unwrap();
+//!
+//! let ids = doc.import(&other, other.roots(), MergePolicy::Reid);
+//! let system = doc.handle(&ids["3535d7f5-7d31-4b97-b772-46fadd300992"]).unwrap();
+//! assert_eq!(doc[system].name(), Some("default"));
+//! ```
+use std::collections::HashMap;
+
+use crate::document::{generate_element_id, ElementId, Kv2Document};
+use crate::element::{Attribute, Element, ElementHandle, ElementRef};
+
+/// What [`Kv2Document::import`] does with an imported element whose id is
+/// already used in the target document
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// Keep the existing element, references to the imported one go to it
+    KeepExisting,
+    /// Replace the class and attributes of the existing element, references
+    /// to it in the target document see the imported content
+    Overwrite,
+    /// Import the element under a new random id
+    Reid,
+}
+
+impl Kv2Document {
+    /// Copies `roots` of `other` and every element they reference into this
+    /// document, returns the ids of the imported elements mapped to their ids
+    /// in this document
+    ///
+    /// Imported elements aren't added to the roots, look them up through the
+    /// returned ids and reference them from where they belong.
+    pub fn import(
+        &mut self,
+        other: &Kv2Document,
+        roots: &[ElementHandle],
+        policy: MergePolicy,
+    ) -> HashMap<ElementId, ElementId> {
+        let mut ids = HashMap::new();
+        let mut handles: HashMap<ElementHandle, ElementHandle> = HashMap::new();
+        let mut imported = Vec::new();
+        let mut added = Vec::new();
+
+        let mut pending: Vec<ElementHandle> = roots.iter().rev().copied().collect();
+        while let Some(handle) = pending.pop() {
+            if handles.contains_key(&handle) {
+                continue;
+            }
+            let Some(element) = other.element(handle) else {
+                continue;
+            };
+
+            let existing = self.handle(element.id());
+            let target = match (existing, policy) {
+                (Some(existing), MergePolicy::KeepExisting) => {
+                    // Whatever the existing element refers to stays as it is
+                    handles.insert(handle, existing);
+                    ids.insert(element.id.clone(), element.id.clone());
+                    continue;
+                }
+                (Some(existing), MergePolicy::Overwrite) => {
+                    self[existing].class_name = element.class_name.clone();
+                    existing
+                }
+                (Some(_), MergePolicy::Reid) => {
+                    let id = loop {
+                        let id = generate_element_id();
+                        if !self.contains(&id) {
+                            break id;
+                        }
+                    };
+                    self.push(Element {
+                        class_name: element.class_name.clone(),
+                        id,
+                        attributes: HashMap::new(),
+                        repeated: Vec::new(),
+                    })
+                }
+                (None, _) => {
+                    let target = self.push(Element {
+                        class_name: element.class_name.clone(),
+                        id: element.id.clone(),
+                        attributes: HashMap::new(),
+                        repeated: Vec::new(),
+                    });
+                    added.push(target);
+                    target
+                }
+            };
+
+            if !element.id.is_empty() {
+                ids.insert(element.id.clone(), self[target].id.clone());
+            }
+            handles.insert(handle, target);
+            imported.push((handle, target));
+
+            pending.extend(
+                element
+                    .references()
+                    .filter_map(|(_, _, target)| target.handle()),
+            );
+        }
+
+        // Dangling references only resolve to elements imported with them
+        let by_id: HashMap<&str, ElementHandle> = handles
+            .iter()
+            .filter(|(handle, _)| !other[**handle].id.is_empty())
+            .map(|(handle, target)| (other[*handle].id.as_str(), *target))
+            .collect();
+
+        // Every reachable element has a target now, copy the attributes over
+        for (handle, target) in imported {
+            let attributes = other[handle]
+                .attributes
+                .iter()
+                .map(|(name, attribute)| (name.clone(), translate(attribute, &handles, &by_id)))
+                .collect();
+            let repeated = other[handle]
+                .repeated
+                .iter()
+                .map(|(name, attribute)| (name.clone(), translate(attribute, &handles, &by_id)))
+                .collect();
+            self[target].attributes = attributes;
+            self[target].repeated = repeated;
+        }
+
+        // New ids can resolve references in this document that waited for them
+        for target in added {
+            let id = self[target].id.clone();
+            if !id.is_empty() && self.handle(&id) == Some(target) {
+                self.link_id(&id, target);
+            }
+        }
+        ids
+    }
+}
+
+fn translate(
+    attribute: &Attribute,
+    handles: &HashMap<ElementHandle, ElementHandle>,
+    by_id: &HashMap<&str, ElementHandle>,
+) -> Attribute {
+    let translate_ref = |target: &ElementRef| match target {
+        ElementRef::Handle(handle) => match handles.get(handle) {
+            Some(handle) => ElementRef::Handle(*handle),
+            None => ElementRef::Null,
+        },
+        ElementRef::Dangling(id) => match by_id.get(id.as_str()) {
+            Some(handle) => ElementRef::Handle(*handle),
+            None => ElementRef::Dangling(id.clone()),
+        },
+        other => other.clone(),
+    };
+
+    match attribute {
+        Attribute::Value(value) => Attribute::Value(value.clone()),
+        Attribute::Element(target) => Attribute::Element(translate_ref(target)),
+        Attribute::ElementArray(targets) => {
+            Attribute::ElementArray(targets.iter().map(translate_ref).collect())
+        }
+    }
+}
@@ -0,0 +1,427 @@
+//! A small path syntax for selecting elements and attributes of a document
+//!
+//! - `DmeModel/children[0]/transform/position` starts at the root elements
+//!   of class `DmeModel` and follows attributes, `[0]` picks an array item
+//! - `//DmeParticleOperator` selects every element of that class reachable
+//!   from the roots, `DmeModel//DmeTransform` every one below a model
+//! - `[functionName="alpha_fade"]` keeps elements whose attribute has that
+//!   value, `*` matches any class or attribute name
+//! - names with special characters can be quoted, `"operator end fadein"`,
+//!   with `\"` for a quote inside the name; whitespace around unquoted
+//!   names is ignored
+//! - `[1]` after a value array attribute selects that item of the array
+//!
+//! # Example
+//! ```rust
+//! use kv2::{parse_kv2_document, KV2Value, Selection};
+//!
+//! let (_, doc) = parse_kv2_document(r#"
+//! "DmeParticleSystemDefinition"
+//! {
+//! "id" "elementid" "3535d7f5-7d31-4b97-b772-46fadd300992"
+//! "operators" "element_array"
+//! [
+//!     "DmeParticleOperator"
+//!     {
+//!         "id" "elementid" "a66571b0-1657-41ad-a160-ba5c3b722835"
+//!         "functionName" "string" "alpha_fade"
+//!         "end_alpha" "float" "0.25"
+//!     }
+//! ]
+//! }
+//! "#).unwrap();
+//!
+//! let selected = doc.select(r#"//DmeParticleOperator[functionName="alpha_fade"]/end_alpha"#).unwrap();
+//! assert!(matches!(selected[0], Selection::Value { value: KV2Value::Double(d), .. } if *d == 0.25));
+//! ```
+use std::collections::HashSet;
+use std::fmt;
+
+use nom::{
+    branch::alt,
+    bytes::complete::{is_not, tag},
+    character::complete::{digit1, multispace0},
+    combinator::{all_consuming, map, map_res, opt},
+    multi::many0,
+    sequence::{delimited, preceded, separated_pair},
+    IResult,
+};
+
+use crate::document::Kv2Document;
+use crate::element::{Attribute, Element, ElementHandle};
+use crate::KV2Value;
+
+/// Something matched by [`Kv2Document::select`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Selection<'a> {
+    Element(ElementHandle),
+    /// A non-element attribute of `element`, or an item of it if the path
+    /// picked one with an index
+    Value {
+        element: ElementHandle,
+        name: &'a str,
+        value: &'a KV2Value,
+    },
+}
+
+/// The path passed to [`Kv2Document::select`] isn't valid
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryError {
+    pub path: String,
+    /// Byte offset in the path where parsing stopped
+    pub offset: usize,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid path {:?} at byte {}", self.path, self.offset)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Axis {
+    /// The first step of a path without `//`, matches root classes
+    Root,
+    /// `/name`, follows an attribute
+    Attribute,
+    /// `//name`, matches classes of elements below
+    Descendant,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Filter {
+    Index(usize),
+    Equals(String, String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Step {
+    axis: Axis,
+    name: String,
+    filters: Vec<Filter>,
+}
+
+impl Kv2Document {
+    /// Selects the elements and attribute values matching `path`, in
+    /// document order without duplicates
+    pub fn select(&self, path: &str) -> Result<Vec<Selection<'_>>, QueryError> {
+        let steps = match parse_path(path) {
+            Ok((_, steps)) => steps,
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                return Err(QueryError {
+                    path: path.to_string(),
+                    offset: path.len() - e.input.len(),
+                })
+            }
+            Err(nom::Err::Incomplete(_)) => {
+                return Err(QueryError {
+                    path: path.to_string(),
+                    offset: path.len(),
+                })
+            }
+        };
+
+        let mut current: Vec<ElementHandle> = Vec::new();
+        for (i, step) in steps.iter().enumerate() {
+            let last = i + 1 == steps.len();
+            let mut selected = Vec::new();
+            match step.axis {
+                Axis::Root => {
+                    let candidates = self.roots().iter().copied();
+                    selected.extend(self.filter_classes(candidates, step));
+                }
+                Axis::Descendant => {
+                    let candidates = if i == 0 {
+                        self.descendants(self.roots(), true)
+                    } else {
+                        self.descendants(&current, false)
+                    };
+                    selected.extend(self.filter_classes(candidates.into_iter(), step));
+                }
+                Axis::Attribute => {
+                    for handle in &current {
+                        self.select_attribute(*handle, step, last, &mut selected);
+                    }
+                }
+            }
+
+            if last {
+                return Ok(dedup(selected));
+            }
+            current = dedup(selected)
+                .into_iter()
+                .filter_map(|selection| match selection {
+                    Selection::Element(handle) => Some(handle),
+                    Selection::Value { .. } => None,
+                })
+                .collect();
+        }
+
+        Ok(Vec::new())
+    }
+
+    fn filter_classes<'a>(
+        &'a self,
+        candidates: impl Iterator<Item = ElementHandle>,
+        step: &Step,
+    ) -> Vec<Selection<'a>> {
+        let mut matches: Vec<ElementHandle> = candidates
+            .filter(|handle| {
+                self.element(*handle).is_some_and(|element| {
+                    (step.name == "*" || element.class_name == step.name)
+                        && self.matches_equals(*handle, &step.filters)
+                })
+            })
+            .collect();
+        for filter in &step.filters {
+            if let Filter::Index(index) = filter {
+                matches = matches.get(*index).copied().into_iter().collect();
+            }
+        }
+        matches.into_iter().map(Selection::Element).collect()
+    }
+
+    fn select_attribute<'a>(
+        &'a self,
+        handle: ElementHandle,
+        step: &Step,
+        last: bool,
+        selected: &mut Vec<Selection<'a>>,
+    ) {
+        let Some(element) = self.element(handle) else {
+            return;
+        };
+        let index = step.filters.iter().find_map(|filter| match filter {
+            Filter::Index(index) => Some(*index),
+            _ => None,
+        });
+        for (name, attribute) in attributes_in_order(element) {
+            if step.name != "*" && *name != step.name {
+                continue;
+            }
+            let targets: Vec<ElementHandle> = match (attribute, index) {
+                (Attribute::Element(target), None) => target.handle().into_iter().collect(),
+                (Attribute::ElementArray(targets), None) => {
+                    targets.iter().filter_map(|t| t.handle()).collect()
+                }
+                (Attribute::ElementArray(targets), Some(i)) => targets
+                    .get(i)
+                    .and_then(|t| t.handle())
+                    .into_iter()
+                    .collect(),
+                (Attribute::Value(value), None) if last => {
+                    selected.push(Selection::Value {
+                        element: handle,
+                        name,
+                        value,
+                    });
+                    continue;
+                }
+                (Attribute::Value(KV2Value::Array(items)), Some(i)) if last => {
+                    if let Some(value) = items.get(i) {
+                        selected.push(Selection::Value {
+                            element: handle,
+                            name,
+                            value,
+                        });
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            selected.extend(
+                targets
+                    .into_iter()
+                    .filter(|target| self.matches_equals(*target, &step.filters))
+                    .map(Selection::Element),
+            );
+        }
+    }
+
+    fn matches_equals(&self, handle: ElementHandle, filters: &[Filter]) -> bool {
+        let Some(element) = self.element(handle) else {
+            return false;
+        };
+        filters.iter().all(|filter| match filter {
+            Filter::Index(_) => true,
+            Filter::Equals(name, expected) => match element.value(name) {
+                Some(value) => value_equals(value, expected),
+                None => name == "id" && element.id() == expected,
+            },
+        })
+    }
+
+    /// Elements reachable from `from` in depth-first order, including `from`
+    /// itself if `include_self` is set
+    fn descendants(&self, from: &[ElementHandle], include_self: bool) -> Vec<ElementHandle> {
+        let mut order = Vec::new();
+        let mut visited = HashSet::new();
+        for handle in from {
+            let mut pending = vec![(*handle, true)];
+            while let Some((handle, is_start)) = pending.pop() {
+                if !is_start || include_self {
+                    if !visited.insert(handle) {
+                        continue;
+                    }
+                    order.push(handle);
+                }
+                let Some(element) = self.element(handle) else {
+                    continue;
+                };
+
+                let mut children = Vec::new();
+                for (_, attribute) in attributes_in_order(element) {
+                    match attribute {
+                        Attribute::Element(target) => children.extend(target.handle()),
+                        Attribute::ElementArray(targets) => {
+                            children.extend(targets.iter().filter_map(|t| t.handle()))
+                        }
+                        Attribute::Value(_) => {}
+                    }
+                }
+                pending.extend(
+                    children
+                        .into_iter()
+                        .rev()
+                        .filter(|child| !visited.contains(child))
+                        .map(|child| (child, false)),
+                );
+            }
+        }
+        order
+    }
+}
+
+/// Attributes in name order, then the later values of repeated ones in
+/// source order
+fn attributes_in_order(element: &Element) -> impl Iterator<Item = (&String, &Attribute)> {
+    let mut names: Vec<&String> = element.attributes.keys().collect();
+    names.sort();
+    names
+        .into_iter()
+        .map(|name| (name, &element.attributes[name]))
+        .chain(
+            element
+                .repeated
+                .iter()
+                .map(|(name, attribute)| (name, attribute)),
+        )
+}
+
+fn value_equals(value: &KV2Value, expected: &str) -> bool {
+    match value {
+        KV2Value::String(s) | KV2Value::Element(s) => s == expected,
+        KV2Value::Bool(b) => match expected {
+            "1" | "true" => *b,
+            "0" | "false" => !*b,
+            _ => false,
+        },
+        KV2Value::Int(i) => expected.parse::<i64>() == Ok(*i),
+        KV2Value::Double(d) => expected.parse::<f64>() == Ok(*d),
+        KV2Value::Vector(v) | KV2Value::Quaternion(v) => {
+            let parsed: Result<Vec<f64>, _> =
+                expected.split_whitespace().map(str::parse::<f64>).collect();
+            parsed.as_ref() == Ok(v)
+        }
+        KV2Value::Array(_) | KV2Value::Object(_) => false,
+    }
+}
+
+fn dedup(selected: Vec<Selection<'_>>) -> Vec<Selection<'_>> {
+    // Repeated attributes share a name, tell their values apart by address
+    let mut seen = HashSet::new();
+    selected
+        .into_iter()
+        .filter(|selection| match selection {
+            Selection::Element(handle) => seen.insert((*handle, std::ptr::null())),
+            Selection::Value { element, value, .. } => {
+                seen.insert((*element, *value as *const KV2Value))
+            }
+        })
+        .collect()
+}
+
+fn parse_path(input: &str) -> IResult<&str, Vec<Step>> {
+    all_consuming(|input| {
+        let (input, _) = multispace0(input)?;
+        let (input, first_axis) = alt((
+            map(tag("//"), |_| Axis::Descendant),
+            map(opt(tag("/")), |_| Axis::Root),
+        ))(input)?;
+        let (input, first) = parse_step(input, first_axis)?;
+        let (input, rest) = many0(|input| {
+            let (input, _) = multispace0(input)?;
+            let (input, axis) = alt((
+                map(tag("//"), |_| Axis::Descendant),
+                map(tag("/"), |_| Axis::Attribute),
+            ))(input)?;
+            parse_step(input, axis)
+        })(input)?;
+        let (input, _) = multispace0(input)?;
+
+        let mut steps = vec![first];
+        steps.extend(rest);
+        Ok((input, steps))
+    })(input)
+}
+
+fn parse_step(input: &str, axis: Axis) -> IResult<&str, Step> {
+    let (input, name) = parse_name(input)?;
+    let (input, filters) = many0(delimited(tag("["), parse_filter, tag("]")))(input)?;
+    Ok((
+        input,
+        Step {
+            axis,
+            name,
+            filters,
+        },
+    ))
+}
+
+fn parse_filter(input: &str) -> IResult<&str, Filter> {
+    alt((
+        map_res(digit1, |digits: &str| digits.parse().map(Filter::Index)),
+        map(
+            separated_pair(
+                parse_name,
+                preceded(multispace0, tag("=")),
+                preceded(multispace0, parse_name),
+            ),
+            |(name, value)| Filter::Equals(name, value),
+        ),
+    ))(input)
+}
+
+fn parse_name(input: &str) -> IResult<&str, String> {
+    alt((
+        delimited(tag("\""), parse_quoted, tag("\"")),
+        map(is_not("/[]=\""), |name: &str| name.trim().to_string()),
+    ))(input)
+}
+
+/// The inside of a quoted name up to the closing quote, `\"` and `\\`
+/// stand for `"` and `\`
+fn parse_quoted(input: &str) -> IResult<&str, String> {
+    let mut name = String::new();
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((&input[i..], name)),
+            '\\' => match chars.next() {
+                Some((_, escaped @ ('"' | '\\'))) => name.push(escaped),
+                Some((_, other)) => {
+                    name.push('\\');
+                    name.push(other);
+                }
+                None => name.push('\\'),
+            },
+            c => name.push(c),
+        }
+    }
+    Err(nom::Err::Error(nom::error::Error::new(
+        &input[input.len()..],
+        nom::error::ErrorKind::TakeUntil,
+    )))
+}
@@ -0,0 +1,405 @@
+//! Parsing from a [`BufRead`] without reading the whole input into memory
+//!
+//! The input is split into tokens as it is read, so besides the reader's
+//! own buffer only the string currently being read is held in memory.
+//!
+//! # Example
+//! ```rust
+//! use std::io::Cursor;
+//! use kv2::{from_reader, KV2Value};
+//!
+//! let input = Cursor::new(r#"
+//! "DmElement"
+//! {
+//! "id" "elementid" "df939bf4-8dd6-435c-9eef-a6e25434ecca"
+//! "name" "string" "root"
+//! }
+//! "#);
+//!
+//! let objects = from_reader(input).unwrap();
+//! assert_eq!(objects[0].fields["name"], KV2Value::String("root".to_string()));
+//! ```
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead};
+
+use crate::encoding::{DecodeError, Encoding};
+use crate::error::{Expected, Kv2Error};
+use crate::events::{Kv2Event, Kv2Events};
+use crate::visit::{Kv2Path, PathSegment};
+use crate::{
+    array_key_value, typed_array_value, typed_value, DuplicateKeyPolicy, Fields, KV2Object,
+    KV2Value, ParseOptions,
+};
+
+/// Why [`from_reader`] failed
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    /// A string that isn't valid UTF-8
+    Decode(DecodeError),
+    Parse(Kv2Error),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Io(error) => write!(f, "{}", error),
+            ReadError::Decode(error) => write!(f, "{}", error),
+            ReadError::Parse(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReadError::Io(error) => Some(error),
+            ReadError::Decode(error) => Some(error),
+            ReadError::Parse(error) => Some(error),
+        }
+    }
+}
+
+impl From<io::Error> for ReadError {
+    fn from(error: io::Error) -> ReadError {
+        ReadError::Io(error)
+    }
+}
+
+/// Where a token starts in the input
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Token {
+    /// A quoted string, unescaped
+    String(String),
+    OpenBrace,
+    CloseBrace,
+    OpenBracket,
+    CloseBracket,
+    Comma,
+    /// A character that can't start a token
+    Unexpected,
+    End,
+}
+
+/// Splits KV2 text read from `R` into tokens, skipping whitespace and
+/// `<!-- -->` comments, and `//` comments if enabled
+pub(crate) struct Tokenizer<R> {
+    reader: R,
+    position: Position,
+    peeked: Option<(Token, Position)>,
+    line_comments: bool,
+    /// Text of the comments skipped and not yet taken
+    comments: VecDeque<String>,
+}
+
+impl<R: BufRead> Tokenizer<R> {
+    pub(crate) fn new(reader: R, options: &ParseOptions) -> Tokenizer<R> {
+        Tokenizer {
+            reader,
+            position: Position {
+                offset: 0,
+                line: 1,
+                column: 1,
+            },
+            peeked: None,
+            line_comments: options.line_comments,
+            comments: VecDeque::new(),
+        }
+    }
+
+    /// The oldest comment skipped while reading tokens
+    pub(crate) fn take_comment(&mut self) -> Option<String> {
+        self.comments.pop_front()
+    }
+
+    pub(crate) fn peek(&mut self) -> Result<&(Token, Position), ReadError> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.read_token()?);
+        }
+        Ok(self.peeked.as_ref().expect("token was just read"))
+    }
+
+    pub(crate) fn next_token(&mut self) -> Result<(Token, Position), ReadError> {
+        match self.peeked.take() {
+            Some(token) => Ok(token),
+            None => self.read_token(),
+        }
+    }
+
+    fn read_token(&mut self) -> Result<(Token, Position), ReadError> {
+        loop {
+            while let Some(byte) = self.peek_byte()?.filter(u8::is_ascii_whitespace) {
+                self.bump(byte);
+            }
+
+            let start = self.position;
+            let Some(byte) = self.next_byte()? else {
+                return Ok((Token::End, start));
+            };
+            let token = match byte {
+                b'"' => Token::String(self.read_string(start)?),
+                b'{' => Token::OpenBrace,
+                b'}' => Token::CloseBrace,
+                b'[' => Token::OpenBracket,
+                b']' => Token::CloseBracket,
+                b',' => Token::Comma,
+                b'<' if self.skip_comment()? => continue,
+                b'/' if self.line_comments && self.peek_byte()? == Some(b'/') => {
+                    self.skip_line_comment()?;
+                    continue;
+                }
+                _ => Token::Unexpected,
+            };
+            return Ok((token, start));
+        }
+    }
+
+    /// Skips the rest of a comment after `<` up to and including `-->`, or
+    /// to the end of the input. Returns `false` if the `<` doesn't start a
+    /// comment.
+    fn skip_comment(&mut self) -> Result<bool, ReadError> {
+        for expected in b"!--" {
+            if self.peek_byte()? != Some(*expected) {
+                return Ok(false);
+            }
+            self.bump(*expected);
+        }
+
+        let mut text = Vec::new();
+        let mut dashes = 0;
+        while let Some(byte) = self.next_byte()? {
+            match byte {
+                b'-' => dashes += 1,
+                b'>' if dashes >= 2 => {
+                    text.truncate(text.len() - 2);
+                    break;
+                }
+                _ => dashes = 0,
+            }
+            text.push(byte);
+        }
+        self.comments
+            .push_back(String::from_utf8_lossy(&text).into_owned());
+        Ok(true)
+    }
+
+    /// Skips the rest of a `//` comment after the first `/`, up to the end
+    /// of the line
+    fn skip_line_comment(&mut self) -> Result<(), ReadError> {
+        self.bump(b'/');
+        let mut text = Vec::new();
+        while let Some(byte) = self.peek_byte()?.filter(|byte| *byte != b'\n') {
+            self.bump(byte);
+            text.push(byte);
+        }
+        if text.last() == Some(&b'\r') {
+            text.pop();
+        }
+        self.comments
+            .push_back(String::from_utf8_lossy(&text).into_owned());
+        Ok(())
+    }
+
+    /// Reads the rest of a string after the opening quote at `start`
+    fn read_string(&mut self, start: Position) -> Result<String, ReadError> {
+        let mut bytes = Vec::new();
+        // Where escapes were collapsed to one byte, to map back to offsets
+        let mut escapes = Vec::new();
+        loop {
+            let Some(byte) = self.next_byte()? else {
+                return Err(ReadError::Parse(Kv2Error {
+                    line: start.line,
+                    column: start.column,
+                    offset: start.offset,
+                    expected: vec![Expected::QuotedString],
+                    path: Kv2Path::new(),
+                }));
+            };
+            match byte {
+                b'"' => break,
+                b'\\' => match self.peek_byte()? {
+                    Some(escaped @ (b'"' | b'\\' | b'n' | b't')) => {
+                        self.bump(escaped);
+                        escapes.push(bytes.len());
+                        bytes.push(match escaped {
+                            b'n' => b'\n',
+                            b't' => b'\t',
+                            other => other,
+                        });
+                    }
+                    _ => bytes.push(b'\\'),
+                },
+                byte => bytes.push(byte),
+            }
+        }
+
+        String::from_utf8(bytes).map_err(|e| {
+            let error = e.utf8_error();
+            let escaped = escapes.iter().filter(|i| **i < error.valid_up_to()).count();
+            ReadError::Decode(DecodeError {
+                encoding: Encoding::Utf8,
+                offset: start.offset + 1 + error.valid_up_to() + escaped,
+                len: error.error_len().unwrap_or(1),
+            })
+        })
+    }
+
+    fn peek_byte(&mut self) -> Result<Option<u8>, ReadError> {
+        loop {
+            match self.reader.fill_buf() {
+                Ok(buffer) => return Ok(buffer.first().copied()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(ReadError::Io(e)),
+            }
+        }
+    }
+
+    fn next_byte(&mut self) -> Result<Option<u8>, ReadError> {
+        let byte = self.peek_byte()?;
+        if let Some(byte) = byte {
+            self.bump(byte);
+        }
+        Ok(byte)
+    }
+
+    fn bump(&mut self, byte: u8) {
+        self.reader.consume(1);
+        self.position.offset += 1;
+        if byte == b'\n' {
+            self.position.line += 1;
+            self.position.column = 1;
+        } else if byte & 0xc0 != 0x80 {
+            // Continuation bytes of a UTF-8 character don't start a column
+            self.position.column += 1;
+        }
+    }
+
+    /// Skips a UTF-8 byte order mark at the start of the input
+    pub(crate) fn skip_bom(&mut self) -> Result<(), ReadError> {
+        if self.reader.fill_buf()?.starts_with(b"\xef\xbb\xbf") {
+            self.reader.consume(3);
+            self.position.offset += 3;
+        }
+        Ok(())
+    }
+}
+
+/// Parses root objects from a reader, requiring the whole input to be valid
+///
+/// The input has to be UTF-8, a byte order mark is skipped. Like
+/// [`parse_kv2`](crate::parse_kv2) the last value wins if an attribute
+/// appears more than once in an element.
+pub fn from_reader<R: BufRead>(reader: R) -> Result<Vec<KV2Object>, ReadError> {
+    from_reader_with(reader, &ParseOptions::default())
+}
+
+/// Parses root objects from a reader like [`from_reader`], accepting the
+/// syntax enabled in `options`
+pub fn from_reader_with<R: BufRead>(
+    reader: R,
+    options: &ParseOptions,
+) -> Result<Vec<KV2Object>, ReadError> {
+    let mut events = Kv2Events::with_options(reader, options);
+    let mut objects = Vec::new();
+    while let Some(event) = events.next() {
+        if let Kv2Event::StartElement { class, .. } = event? {
+            objects.push(read_element(&mut events, class, options.duplicate_keys)?);
+        }
+    }
+    Ok(objects)
+}
+
+/// Collects the attributes of an element after its `StartElement`
+fn read_element<R: BufRead>(
+    events: &mut Kv2Events<R>,
+    class_name: String,
+    policy: DuplicateKeyPolicy,
+) -> Result<KV2Object, ReadError> {
+    let mut fields = Fields::new(policy);
+    loop {
+        let event = next_event(events)?;
+        let start = events.start();
+        let (key, value) = match event {
+            Kv2Event::EndElement => {
+                let (fields, repeated) = fields.finish();
+                return Ok(KV2Object {
+                    class_name,
+                    fields,
+                    repeated,
+                });
+            }
+            Kv2Event::Attribute {
+                key,
+                data_type,
+                raw,
+            } => (key, typed_value(&data_type, raw.into()).into_owned()),
+            Kv2Event::StartElement { key, class } => {
+                let object = KV2Value::Object(read_element(events, class, policy)?);
+                (key.unwrap_or_default(), object)
+            }
+            Kv2Event::StartArray { key, data_type } => {
+                let base_type = data_type.strip_suffix("_array").unwrap_or_default();
+                (key, array_items(events, base_type, policy)?)
+            }
+            event => unreachable!("{:?} outside of an array", event),
+        };
+
+        if policy == DuplicateKeyPolicy::Error && fields.contains(&key) {
+            return Err(ReadError::Parse(Kv2Error {
+                line: start.line,
+                column: start.column,
+                offset: start.offset,
+                expected: vec![Expected::UniqueAttribute],
+                path: events.path().join(PathSegment::Attribute(key)),
+            }));
+        }
+        fields.insert(key, value);
+    }
+}
+
+/// Collects the items of an array after its `StartArray`
+fn array_items<R: BufRead>(
+    events: &mut Kv2Events<R>,
+    base_type: &str,
+    policy: DuplicateKeyPolicy,
+) -> Result<KV2Value, ReadError> {
+    let mut items = Vec::new();
+    loop {
+        items.push(match next_event(events)? {
+            Kv2Event::EndArray => return Ok(KV2Value::Array(items)),
+            Kv2Event::Item { key: None, raw } => {
+                typed_array_value(base_type, raw.into()).into_owned()
+            }
+            Kv2Event::Item {
+                key: Some(key),
+                raw,
+            } => array_key_value(key.into(), raw.into()).into_owned(),
+            Kv2Event::StartElement { class, .. } => {
+                KV2Value::Object(read_element(events, class, policy)?)
+            }
+            event => unreachable!("{:?} inside an array", event),
+        });
+    }
+}
+
+/// The next event inside an element or array other than a comment, an
+/// element or array always ends before the events do
+fn next_event<R: BufRead>(events: &mut Kv2Events<R>) -> Result<Kv2Event, ReadError> {
+    loop {
+        match events
+            .next()
+            .expect("an element or array ends before the events do")?
+        {
+            Kv2Event::Comment(_) => continue,
+            event => return Ok(event),
+        }
+    }
+}
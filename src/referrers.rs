@@ -0,0 +1,198 @@
+//! Finding the elements that refer to an element
+//!
+//! # Example
+//! ```rust
+//! use kv2::parse_kv2_document;
+//!
+//! let (_, mut doc) = parse_kv2_document(r#"
+//! "DmElement"
+//! {
+//! "id" "elementid" "df939bf4-8dd6-435c-9eef-a6e25434ecca"
+//! "skeleton" "element" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+//! "models" "element_array" [ "element" "90e0ae34-0671-478d-95f5-12fa5c905c7a" ]
+//! }
+//! "DmeModel"
+//! {
+//! "id" "elementid" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+//! }
+//! "#).unwrap();
+//!
+//! let referrers = doc.referrers("90e0ae34-0671-478d-95f5-12fa5c905c7a");
+//! assert_eq!(referrers.len(), 2);
+//! assert_eq!(referrers[0].attribute, "models");
+//! assert_eq!(referrers[0].index, Some(0));
+//!
+//! doc[referrers[1].element].remove("skeleton");
+//! assert_eq!(doc.referrers("90e0ae34-0671-478d-95f5-12fa5c905c7a").len(), 1);
+//! ```
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, PoisonError};
+
+use crate::document::{ElementId, Kv2Document};
+use crate::element::{Element, ElementHandle, ElementRef};
+
+/// A reference to an element, found by [`Kv2Document::referrers`]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Referrer {
+    /// The element holding the reference
+    pub element: ElementHandle,
+    pub attribute: String,
+    /// Position in the `element_array`, if the reference is an array item
+    pub index: Option<usize>,
+}
+
+/// Every reference in a document keyed by its target
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ReferrerIndex {
+    by_handle: HashMap<ElementHandle, Vec<Referrer>>,
+    /// Dangling references, by the id they point at
+    by_id: HashMap<ElementId, Vec<Referrer>>,
+    /// What each element refers to, to take its entries out again
+    targets: HashMap<ElementHandle, Vec<ElementRef>>,
+}
+
+impl ReferrerIndex {
+    pub(crate) fn build(document: &Kv2Document) -> ReferrerIndex {
+        let mut index = ReferrerIndex::default();
+        for (handle, element) in document.elements() {
+            index.add(handle, element);
+        }
+        index
+    }
+
+    /// Elements holding dangling references to `id`
+    pub(crate) fn dangling(&self, id: &str) -> impl Iterator<Item = ElementHandle> + '_ {
+        let referrers = self.by_id.get(id).map_or(&[][..], Vec::as_slice);
+        // Sorted by element, so every element comes once
+        let mut last = None;
+        referrers
+            .iter()
+            .map(|referrer| referrer.element)
+            .filter(move |handle| last.replace(*handle) != Some(*handle))
+    }
+
+    fn add(&mut self, handle: ElementHandle, element: &Element) {
+        let mut targets = Vec::new();
+        for (attribute, array_index, target) in element.references() {
+            let referrer = Referrer {
+                element: handle,
+                attribute: attribute.to_string(),
+                index: array_index,
+            };
+            let referrers = match target {
+                ElementRef::Null => continue,
+                ElementRef::Handle(target) => self.by_handle.entry(*target).or_default(),
+                ElementRef::Dangling(id) => self.by_id.entry(id.clone()).or_default(),
+            };
+            // Keep every list sorted so lookups don't have to
+            let at = referrers.partition_point(|r| *r < referrer);
+            referrers.insert(at, referrer);
+            targets.push(target.clone());
+        }
+        if !targets.is_empty() {
+            self.targets.insert(handle, targets);
+        }
+    }
+
+    fn remove(&mut self, handle: ElementHandle) {
+        for target in self.targets.remove(&handle).unwrap_or_default() {
+            let referrers = match &target {
+                ElementRef::Handle(target) => self.by_handle.get_mut(target),
+                ElementRef::Dangling(id) => self.by_id.get_mut(id),
+                ElementRef::Null => None,
+            };
+            let Some(referrers) = referrers else {
+                continue;
+            };
+            referrers.retain(|r| r.element != handle);
+            if referrers.is_empty() {
+                match target {
+                    ElementRef::Handle(target) => self.by_handle.remove(&target),
+                    ElementRef::Dangling(id) => self.by_id.remove(&id),
+                    ElementRef::Null => None,
+                };
+            }
+        }
+    }
+}
+
+/// The referrer index of a document, built by the first query and then
+/// kept up to date by re-indexing only the elements edited since
+#[derive(Debug, Default)]
+pub(crate) struct ReferrerCache(Mutex<CacheState>);
+
+#[derive(Debug, Clone, Default)]
+struct CacheState {
+    index: Option<ReferrerIndex>,
+    /// Elements whose references may have changed since the last query
+    edited: HashSet<ElementHandle>,
+}
+
+impl ReferrerCache {
+    /// Notes that the references of `handle` may change
+    pub(crate) fn touch(&mut self, handle: ElementHandle) {
+        let state = self.0.get_mut().unwrap_or_else(PoisonError::into_inner);
+        if state.index.is_some() {
+            state.edited.insert(handle);
+        }
+    }
+
+    /// Runs `query` on the index brought up to date with `document`
+    pub(crate) fn query<T>(
+        &self,
+        document: &Kv2Document,
+        query: impl FnOnce(&ReferrerIndex) -> T,
+    ) -> T {
+        let mut state = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let CacheState { index, edited } = &mut *state;
+        let index = index.get_or_insert_with(|| {
+            edited.clear();
+            ReferrerIndex::build(document)
+        });
+        for handle in edited.drain() {
+            index.remove(handle);
+            if let Some(element) = document.element(handle) {
+                index.add(handle, element);
+            }
+        }
+        query(index)
+    }
+}
+
+impl Clone for ReferrerCache {
+    fn clone(&self) -> ReferrerCache {
+        let state = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        ReferrerCache(Mutex::new(state.clone()))
+    }
+}
+
+impl Kv2Document {
+    /// Every reference to the element with id `id`, including dangling
+    /// references to it if no element has that id, ordered by referring
+    /// element, attribute name and array index
+    ///
+    /// The index behind this is built by the first call, later calls only
+    /// re-index the elements edited since.
+    pub fn referrers(&self, id: &str) -> Vec<Referrer> {
+        self.referrer_cache().query(self, |index| {
+            let mut referrers: Vec<Referrer> = self
+                .handle(id)
+                .and_then(|handle| index.by_handle.get(&handle))
+                .into_iter()
+                .chain(index.by_id.get(id))
+                .flatten()
+                .cloned()
+                .collect();
+            referrers.sort();
+            referrers
+        })
+    }
+
+    /// Every reference to the element behind `handle`, which works for
+    /// elements without an id too
+    pub fn referrers_of(&self, handle: ElementHandle) -> Vec<Referrer> {
+        self.referrer_cache().query(self, |index| {
+            index.by_handle.get(&handle).cloned().unwrap_or_default()
+        })
+    }
+}
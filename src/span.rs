@@ -0,0 +1,82 @@
+//! Where parsed elements and attributes are in the source
+//!
+//! Documents parsed from text remember the span of every element, attribute
+//! and array item, so problems found later can point back at the text.
+//!
+//! # Example
+//! ```rust
+//! use kv2::parse_document;
+//!
+//! let input = r#""DmElement"
+//! {
+//!     "name" "string" "root"
+//! }
+//! "#;
+//!
+//! let doc = parse_document(input).unwrap();
+//! let root = doc.roots()[0];
+//! let name = doc.attribute_span(root, "name").unwrap();
+//! assert_eq!(&input[name.range()], r#""name" "string" "root""#);
+//! assert_eq!((name.line, name.column), (3, 5));
+//! assert_eq!(doc.span(root).unwrap().range(), 0..input.len() - 1);
+//! ```
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::visit::Kv2Path;
+
+/// A range of the source with the position of its start
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span {
+    /// Byte offset of the first character
+    pub start: usize,
+    /// Byte offset just past the last character
+    pub end: usize,
+    /// 1-based line of `start`
+    pub line: usize,
+    /// 1-based column of `start`, in characters
+    pub column: usize,
+}
+
+impl Span {
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+}
+
+/// Spans recorded while parsing, by the path of what they cover
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SourceMap {
+    /// From the class name to the closing `}`
+    pub elements: HashMap<Kv2Path, Span>,
+    /// From the key to the end of the value for attributes, the whole item
+    /// for array items
+    pub values: HashMap<Kv2Path, Span>,
+}
+
+/// Finds lines by offset without scanning the source from the start
+#[derive(Debug, Clone)]
+pub(crate) struct LineIndex {
+    /// Byte offset of the start of every line
+    starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub(crate) fn new(source: &str) -> LineIndex {
+        let starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        LineIndex { starts }
+    }
+
+    pub(crate) fn span(&self, source: &str, range: Range<usize>) -> Span {
+        let line = self.starts.partition_point(|start| *start <= range.start);
+        let line_start = self.starts[line - 1];
+        Span {
+            start: range.start,
+            end: range.end,
+            line,
+            column: source[line_start..range.start].chars().count() + 1,
+        }
+    }
+}
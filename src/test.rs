@@ -283,6 +283,130 @@ mod tests {
     }
 }
 
+#[cfg(test)]
+mod float_tests {
+    use crate::{parse_kv2, write_kv2, FloatSpelling, KV2Value, WriterOptions};
+
+    #[test]
+    fn parse_msvc_special_floats() {
+        let input = r#"
+"DmElement"
+{
+    "id" "elementid" "df939bf4-8dd6-435c-9eef-a6e25434ecca"
+    "inf" "float" "1.#INF"
+    "neg_inf" "float" "-1.#INF00"
+    "ind" "float" "-1.#IND"
+    "qnan" "float" "1.#QNAN0"
+    "bounds" "vector3" "-1.#INF 0 1.#INF"
+    "values" "float_array"
+    [
+        "1.5",
+        "-1.#IND"
+    ]
+}
+"#;
+        let (_, objects) = parse_kv2(input).expect("expected the test: msvc floats to parse");
+        let fields = &objects[0].fields;
+
+        assert!(matches!(fields["inf"], KV2Value::Double(d) if d == f64::INFINITY));
+        assert!(matches!(fields["neg_inf"], KV2Value::Double(d) if d == f64::NEG_INFINITY));
+        assert!(matches!(fields["ind"], KV2Value::Double(d) if d.is_nan()));
+        assert!(matches!(fields["qnan"], KV2Value::Double(d) if d.is_nan()));
+        match &fields["bounds"] {
+            KV2Value::Vector(v) => {
+                assert_eq!(v, &vec![f64::NEG_INFINITY, 0.0, f64::INFINITY])
+            }
+            other => panic!("expected a vector, got {:?}", other),
+        }
+        match &fields["values"] {
+            KV2Value::Array(values) => {
+                assert!(matches!(values[0], KV2Value::Double(d) if d == 1.5));
+                assert!(matches!(values[1], KV2Value::Double(d) if d.is_nan()));
+            }
+            other => panic!("expected an array, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_rounded_msvc_floats() {
+        let parse = |value: &str| {
+            let input = format!("\"DmElement\" {{ \"value\" \"float\" \"{}\" }}", value);
+            let (_, objects) = parse_kv2(&input).unwrap();
+            match objects[0].fields["value"] {
+                KV2Value::Double(d) => d,
+                ref other => panic!("expected a float, got {:?}", other),
+            }
+        };
+        assert_eq!(parse("1.#J"), f64::INFINITY);
+        assert_eq!(parse("-1.#IO"), f64::NEG_INFINITY);
+        assert_eq!(parse("1.$"), f64::INFINITY);
+        assert!(parse("1.#R").is_nan());
+        assert!(parse("1.#QO").is_nan());
+        assert!(parse("-1.#IND000").is_nan());
+    }
+
+    #[test]
+    fn write_special_floats() {
+        let input = r#"
+"DmElement"
+{
+    "id" "elementid" "df939bf4-8dd6-435c-9eef-a6e25434ecca"
+    "inf" "float" "inf"
+    "ind" "float" "-1.#IND"
+}
+"#;
+        let (_, objects) = parse_kv2(input).unwrap();
+
+        let portable = write_kv2(&objects, &WriterOptions::default());
+        assert!(portable.contains("\"inf\" \"float\" \"inf\""));
+        assert!(portable.contains("\"ind\" \"float\" \"nan\""));
+
+        let msvc = write_kv2(
+            &objects,
+            &WriterOptions {
+                float_spelling: FloatSpelling::Msvc,
+            },
+        );
+        assert!(msvc.contains("\"inf\" \"float\" \"1.#INF\""));
+        assert!(msvc.contains("\"ind\" \"float\" \"-1.#IND\""));
+
+        // the written document parses back to the same values
+        let (_, reparsed) = parse_kv2(&msvc).unwrap();
+        assert!(matches!(reparsed[0].fields["inf"], KV2Value::Double(d) if d == f64::INFINITY));
+        assert!(matches!(reparsed[0].fields["ind"], KV2Value::Double(d) if d.is_nan()));
+    }
+
+    #[test]
+    fn vector_sizes_round_trip() {
+        let input = r#"
+"DmElement"
+{
+    "uv" "vector2" "0.5 1"
+    "plane" "vector4" "0 0 1 8"
+    "angles" "qangle" "0 90 0"
+    "tint" "color" "255 128 0 255"
+    "corners" "vector2_array" [ "0 0", "1 1" ]
+}
+"#;
+        let (_, objects) = parse_kv2(input).unwrap();
+        let fields = &objects[0].fields;
+        assert_eq!(fields["uv"], KV2Value::Vector(vec![0.5, 1.0]));
+        assert_eq!(fields["angles"], KV2Value::Vector(vec![0.0, 90.0, 0.0]));
+        assert_eq!(
+            fields["tint"],
+            KV2Value::Vector(vec![255.0, 128.0, 0.0, 255.0])
+        );
+
+        let output = write_kv2(&objects, &WriterOptions::default());
+        assert!(output.contains("\"uv\" \"vector2\" \"0.5 1\""));
+        assert!(output.contains("\"angles\" \"vector3\" \"0 90 0\""));
+        assert!(output.contains("\"tint\" \"vector4\" \"255 128 0 255\""));
+
+        let (_, reparsed) = parse_kv2(&output).unwrap();
+        assert_eq!(reparsed[0].fields, objects[0].fields);
+    }
+}
+
 #[cfg(feature = "serde")]
 #[cfg(test)]
 mod serde_tests {
@@ -561,3 +685,2266 @@ mod serde_tests {
         }
     }
 }
+
+#[cfg(test)]
+mod document_tests {
+    use crate::{parse_kv2_document, Attribute, DanglingReference, ElementRef, KV2Value};
+    use log::error;
+
+    #[test]
+    fn document_header_and_index() {
+        let input = r#"
+<!-- dmx encoding keyvalues2 1 format model 18 -->
+"DmElement"
+{
+    "id" "elementid" "df939bf4-8dd6-435c-9eef-a6e25434ecca"
+    "name" "string" "root"
+    "exportTags" "DmeExportTags"
+    {
+        "id" "elementid" "9891f8a4-debd-488a-81cd-3d0f02345c74"
+        "name" "string" "exportTags"
+    }
+}
+
+"DmeModel"
+{
+    "id" "elementid" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+    "baseStates" "element_array"
+    [
+        "DmeTransformsList"
+        {
+            "id" "elementid" "b4115142-4f81-4569-8c9a-3bdcded9b36f"
+            "transforms" "element_array"
+            [
+                "DmeTransform"
+                {
+                    "id" "elementid" "9eac606c-1fc5-474f-b17f-9fc503b8a7ae"
+                    "position" "vector3" "0 0 0"
+                }
+            ]
+        }
+    ]
+}
+"#;
+        match parse_kv2_document(input) {
+            Ok((_, doc)) => {
+                let header = doc.header().expect("expected a header");
+                assert_eq!(header.encoding, "keyvalues2");
+                assert_eq!(header.encoding_version, 1);
+                assert_eq!(header.format, "model");
+                assert_eq!(header.format_version, 18);
+
+                assert_eq!(doc.roots().len(), 2);
+                assert_eq!(doc.len(), 5);
+                assert_eq!(
+                    doc.get("9891f8a4-debd-488a-81cd-3d0f02345c74")
+                        .unwrap()
+                        .class_name,
+                    "DmeExportTags"
+                );
+                assert_eq!(
+                    doc.get("9eac606c-1fc5-474f-b17f-9fc503b8a7ae")
+                        .unwrap()
+                        .class_name,
+                    "DmeTransform"
+                );
+                assert!(doc.get("00000000-0000-0000-0000-000000000000").is_none());
+            }
+            Err(e) => {
+                error!("{:?}", e);
+                panic!("expected the test: document_header_and_index to pass")
+            }
+        }
+    }
+
+    #[test]
+    fn document_legacy_header() {
+        let input = r#"
+<!-- DMXVersion keyvalues2_v1 -->
+"DmElement"
+{
+    "id" "elementid" "df939bf4-8dd6-435c-9eef-a6e25434ecca"
+}
+"#;
+        let (_, doc) = parse_kv2_document(input).unwrap();
+        let header = doc.header().expect("expected a header");
+        assert_eq!(header.encoding, "keyvalues2");
+        assert_eq!(header.encoding_version, 1);
+        assert_eq!(header.format, "");
+        assert!(doc.contains("df939bf4-8dd6-435c-9eef-a6e25434ecca"));
+    }
+
+    #[test]
+    fn document_resolve_references() {
+        let input = r#"
+"DmElement"
+{
+    "id" "elementid" "df939bf4-8dd6-435c-9eef-a6e25434ecca"
+    "name" "string" "root"
+    "skeleton" "element" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+    "model" "element" ""
+}
+
+"DmeModel"
+{
+    "id" "elementid" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+    "children" "element_array"
+    [
+        "element" "56f186a9-1316-46c1-b82d-f46d5f19e19e",
+        "element" "a6d7e5f8-ba52-4c81-9bdf-4b0fb6892de9"
+    ]
+    "transform" "DmeTransform"
+    {
+        "id" "elementid" "56f186a9-1316-46c1-b82d-f46d5f19e19e"
+        "position" "vector3" "0 0 0"
+    }
+}
+"#;
+        let (_, doc) = parse_kv2_document(input).unwrap();
+        let root = &doc[doc.roots()[0]];
+
+        // forward reference to an element defined later in the file
+        let skeleton = root.child("skeleton").expect("expected skeleton");
+        assert_eq!(doc[skeleton].class_name, "DmeModel");
+        assert_eq!(Some(skeleton), doc.roots().get(1).copied());
+        // null reference
+        assert_eq!(
+            root.get("model"),
+            Some(&Attribute::Element(ElementRef::Null))
+        );
+        assert!(root.child("model").is_none());
+
+        let children = doc[skeleton].children("children");
+        assert_eq!(children, vec![doc[skeleton].child("transform").unwrap()]);
+        assert_eq!(doc[children[0]].class_name, "DmeTransform");
+
+        let dangling = doc.dangling_references();
+        assert_eq!(
+            dangling,
+            vec![DanglingReference {
+                element: skeleton,
+                attribute: "children".to_string(),
+                index: Some(1),
+                target: "a6d7e5f8-ba52-4c81-9bdf-4b0fb6892de9".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn document_shared_and_cyclic_elements() {
+        let input = r#"
+"DmeModel"
+{
+    "id" "elementid" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+    "children" "element_array"
+    [
+        "DmeJoint"
+        {
+            "id" "elementid" "a6d7e5f8-ba52-4c81-9bdf-4b0fb6892de9"
+            "name" "string" "pelvis"
+            "parent" "element" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+        }
+    ]
+    "jointList" "element_array"
+    [
+        "element" "a6d7e5f8-ba52-4c81-9bdf-4b0fb6892de9"
+    ]
+}
+"#;
+        let (_, mut doc) = parse_kv2_document(input).unwrap();
+        assert_eq!(doc.len(), 2);
+
+        let model = doc.roots()[0];
+        let joint = doc.handle("a6d7e5f8-ba52-4c81-9bdf-4b0fb6892de9").unwrap();
+        // the joint exists once, shared by both arrays, and points back at its parent
+        assert_eq!(doc[model].children("children"), vec![joint]);
+        assert_eq!(doc[model].children("jointList"), vec![joint]);
+        assert_eq!(doc[joint].child("parent"), Some(model));
+
+        // mutate through the handle, every referrer sees the change
+        doc[joint].set_value("name", KV2Value::String("hips".to_string()));
+        let shared = doc[model].children("jointList")[0];
+        assert_eq!(doc[shared].name(), Some("hips"));
+
+        // ids can change without breaking references
+        doc.set_id(joint, "4a3f1c2e-0000-4000-8000-000000000001");
+        assert_eq!(
+            doc.handle("4a3f1c2e-0000-4000-8000-000000000001"),
+            Some(joint)
+        );
+        assert!(!doc.contains("a6d7e5f8-ba52-4c81-9bdf-4b0fb6892de9"));
+
+        // converting back writes the joint inline once and references it after that
+        let objects = doc.to_objects();
+        match &objects[0].fields["children"] {
+            KV2Value::Array(values) => {
+                assert!(matches!(&values[0], KV2Value::Object(o) if o.class_name == "DmeJoint"))
+            }
+            other => panic!("expected an array, got {:?}", other),
+        }
+        assert_eq!(
+            objects[0].fields["jointList"],
+            KV2Value::Array(vec![KV2Value::Element(
+                "4a3f1c2e-0000-4000-8000-000000000001".to_string()
+            )])
+        );
+
+        // removing an element leaves dangling references to its id
+        doc.remove_element(joint);
+        assert_eq!(doc.len(), 1);
+        assert_eq!(doc.dangling_references().len(), 2);
+    }
+
+    #[test]
+    fn edits_link_only_the_ids_they_add() {
+        let mut doc = crate::Kv2Document::new(None, Vec::new());
+        let holder = doc.add_element("DmElement", "holder");
+        doc[holder].set(
+            "target",
+            Attribute::Element(ElementRef::Dangling("a".into())),
+        );
+
+        // a new element resolves the references waiting for its id
+        let first = doc.add_element("DmElement", "a");
+        assert_eq!(doc[holder].child("target"), Some(first));
+
+        // an element sharing the id doesn't take references over
+        let second = doc.add_element("DmElement", "a");
+        assert_eq!(doc.handle("a"), Some(first));
+
+        // removing the first one moves them to the one left
+        doc.remove_element(first);
+        assert_eq!(doc.handle("a"), Some(second));
+        assert_eq!(doc[holder].child("target"), Some(second));
+
+        // the id stays indexed while any element has it
+        let third = doc.add_element("DmElement", "b");
+        doc.set_id(third, "a");
+        doc.set_id(second, "c");
+        assert_eq!(doc.handle("a"), Some(third));
+        assert_eq!(doc[holder].child("target"), Some(second));
+
+        // inserted objects link their own references and the ones waiting for them
+        doc[holder].set(
+            "other",
+            Attribute::Element(ElementRef::Dangling("d".into())),
+        );
+        let object = crate::KV2Object {
+            class_name: "DmElement".to_string(),
+            fields: [
+                ("id".to_string(), KV2Value::String("d".to_string())),
+                ("back".to_string(), KV2Value::Element("holder".to_string())),
+            ]
+            .into(),
+            repeated: Vec::new(),
+        };
+        let inserted = doc.insert_object(object);
+        assert_eq!(doc[holder].child("other"), Some(inserted));
+        assert_eq!(doc[inserted].child("back"), Some(holder));
+        assert!(doc.dangling_references().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod gc_tests {
+    use crate::{parse_kv2_document, Attribute, ElementRef};
+
+    #[test]
+    fn collect_garbage_removes_unreachable_elements() {
+        let input = r#"
+"DmElement"
+{
+    "id" "elementid" "df939bf4-8dd6-435c-9eef-a6e25434ecca"
+    "model" "DmeModel"
+    {
+        "id" "elementid" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+        "transform" "DmeTransform"
+        {
+            "id" "elementid" "56f186a9-1316-46c1-b82d-f46d5f19e19e"
+            "position" "vector3" "0 0 0"
+        }
+        "shape" "DmeMesh"
+        {
+            "id" "elementid" "b4115142-4f81-4569-8c9a-3bdcded9b36f"
+            "owner" "element" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+        }
+    }
+}
+"#;
+        let (_, mut doc) = parse_kv2_document(input).unwrap();
+        assert!(doc.collect_garbage().is_empty());
+
+        // orphan the mesh, which still refers back to the model
+        let model = doc.handle("90e0ae34-0671-478d-95f5-12fa5c905c7a").unwrap();
+        doc[model].set("shape", Attribute::Element(ElementRef::Null));
+
+        let removed = doc.collect_garbage();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].1.class_name, "DmeMesh");
+        assert_eq!(doc.len(), 3);
+        assert!(!doc.contains("b4115142-4f81-4569-8c9a-3bdcded9b36f"));
+        assert!(doc.dangling_references().is_empty());
+
+        // keep only what the model can reach
+        let removed = doc.collect_garbage_from(&[model]);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].1.class_name, "DmElement");
+        assert!(doc.roots().is_empty());
+        assert_eq!(doc.len(), 2);
+    }
+}
+
+#[cfg(test)]
+mod id_tests {
+    use std::collections::HashMap;
+
+    use crate::{generate_element_id, parse_kv2_document, KV2Value};
+
+    const INPUT: &str = r#"
+"DmeModel"
+{
+    "id" "elementid" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+    "children" "element_array"
+    [
+        "DmeJoint"
+        {
+            "id" "elementid" "a6d7e5f8-ba52-4c81-9bdf-4b0fb6892de9"
+            "parent" "element" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+        }
+    ]
+    "jointList" "element_array"
+    [
+        "element" "a6d7e5f8-ba52-4c81-9bdf-4b0fb6892de9",
+        "element" "ae60ee94-7c9a-494c-be01-eba193e90146"
+    ]
+}
+"#;
+
+    #[test]
+    fn remap_ids_rewrites_ids_and_references() {
+        let (_, mut doc) = parse_kv2_document(INPUT).unwrap();
+        let joint = doc.handle("a6d7e5f8-ba52-4c81-9bdf-4b0fb6892de9").unwrap();
+
+        let ids = HashMap::from([
+            (
+                "a6d7e5f8-ba52-4c81-9bdf-4b0fb6892de9".to_string(),
+                "00000000-0000-4000-8000-000000000001".to_string(),
+            ),
+            (
+                "ae60ee94-7c9a-494c-be01-eba193e90146".to_string(),
+                "00000000-0000-4000-8000-000000000002".to_string(),
+            ),
+        ]);
+        doc.remap_ids(&ids);
+
+        assert_eq!(
+            doc.handle("00000000-0000-4000-8000-000000000001"),
+            Some(joint)
+        );
+        assert!(!doc.contains("a6d7e5f8-ba52-4c81-9bdf-4b0fb6892de9"));
+        assert!(doc.contains("90e0ae34-0671-478d-95f5-12fa5c905c7a"));
+
+        let objects = doc.to_objects();
+        assert_eq!(
+            objects[0].fields["jointList"],
+            KV2Value::Array(vec![
+                KV2Value::Element("00000000-0000-4000-8000-000000000001".to_string()),
+                KV2Value::Element("00000000-0000-4000-8000-000000000002".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn regenerate_ids_gives_every_element_a_new_id() {
+        let (_, mut doc) = parse_kv2_document(INPUT).unwrap();
+        let model = doc.roots()[0];
+        let joint = doc.handle("a6d7e5f8-ba52-4c81-9bdf-4b0fb6892de9").unwrap();
+
+        let ids = doc.regenerate_ids();
+        assert_eq!(ids.len(), 2);
+
+        let new_joint_id = &ids["a6d7e5f8-ba52-4c81-9bdf-4b0fb6892de9"];
+        assert_eq!(doc.handle(new_joint_id), Some(joint));
+        assert_eq!(doc[joint].id(), new_joint_id);
+        assert_eq!(doc[joint].child("parent"), Some(model));
+        assert_ne!(doc[model].id(), "90e0ae34-0671-478d-95f5-12fa5c905c7a");
+        // the reference to an element outside the document stays as it was
+        assert_eq!(doc.dangling_references().len(), 1);
+    }
+
+    #[test]
+    fn regenerate_ids_leaves_elements_without_an_id() {
+        let (_, mut doc) = parse_kv2_document(INPUT).unwrap();
+        let anonymous = doc.add_element("DmElement", "");
+
+        let ids = doc.regenerate_ids();
+        assert_eq!(ids.len(), 2);
+        assert_eq!(doc[anonymous].id(), "");
+        assert_eq!(doc.ids().count(), 2);
+    }
+
+    #[test]
+    fn generated_ids_are_v4_uuids() {
+        let id = generate_element_id();
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "4");
+        assert!(matches!(&id[19..20], "8" | "9" | "a" | "b"));
+        assert_ne!(id, generate_element_id());
+    }
+}
+
+#[cfg(test)]
+mod merge_tests {
+    use crate::{parse_kv2_document, Attribute, ElementRef, KV2Value, Kv2Document, MergePolicy};
+
+    fn documents() -> (Kv2Document, Kv2Document) {
+        let (_, doc) = parse_kv2_document(
+            r#"
+"DmElement"
+{
+    "id" "elementid" "833dbad4-0848-4c77-a49c-5a702a545c55"
+    "particleSystemDefinitions" "element_array"
+    [
+        "DmeParticleSystemDefinition"
+        {
+            "id" "elementid" "3535d7f5-7d31-4b97-b772-46fadd300992"
+            "name" "string" "existing"
+        }
+    ]
+}
+"#,
+        )
+        .unwrap();
+
+        let (_, other) = parse_kv2_document(
+            r#"
+"DmeParticleSystemDefinition"
+{
+    "id" "elementid" "3535d7f5-7d31-4b97-b772-46fadd300992"
+    "name" "string" "imported"
+    "operators" "element_array"
+    [
+        "DmeParticleOperator"
+        {
+            "id" "elementid" "a66571b0-1657-41ad-a160-ba5c3b722835"
+            "functionName" "string" "alpha_fade"
+            "system" "element" "3535d7f5-7d31-4b97-b772-46fadd300992"
+        }
+    ]
+}
+
+"DmeParticleSystemDefinition"
+{
+    "id" "elementid" "9c8b45bf-2644-4c8f-9a37-0873b578aeb7"
+    "name" "string" "not imported"
+}
+"#,
+        )
+        .unwrap();
+
+        (doc, other)
+    }
+
+    #[test]
+    fn import_keep_existing() {
+        let (mut doc, other) = documents();
+        let ids = doc.import(&other, &other.roots()[..1], MergePolicy::KeepExisting);
+
+        assert_eq!(ids.len(), 1);
+        assert_eq!(doc.len(), 2);
+        assert_eq!(
+            doc.get("3535d7f5-7d31-4b97-b772-46fadd300992")
+                .unwrap()
+                .name(),
+            Some("existing")
+        );
+        assert!(!doc.contains("a66571b0-1657-41ad-a160-ba5c3b722835"));
+    }
+
+    #[test]
+    fn import_overwrite() {
+        let (mut doc, other) = documents();
+        let system = doc.handle("3535d7f5-7d31-4b97-b772-46fadd300992").unwrap();
+        let ids = doc.import(&other, &other.roots()[..1], MergePolicy::Overwrite);
+
+        assert_eq!(ids.len(), 2);
+        assert_eq!(doc.len(), 3);
+        assert!(!doc.contains("9c8b45bf-2644-4c8f-9a37-0873b578aeb7"));
+        // the existing element keeps its handle and takes the imported content
+        assert_eq!(doc[system].name(), Some("imported"));
+        let operator = doc[system].children("operators")[0];
+        assert_eq!(doc[operator].child("system"), Some(system));
+        assert!(doc.dangling_references().is_empty());
+    }
+
+    #[test]
+    fn import_reid() {
+        let (mut doc, other) = documents();
+        let ids = doc.import(&other, &other.roots()[..1], MergePolicy::Reid);
+
+        assert_eq!(doc.len(), 4);
+        let new_id = &ids["3535d7f5-7d31-4b97-b772-46fadd300992"];
+        assert_ne!(new_id, "3535d7f5-7d31-4b97-b772-46fadd300992");
+        // ids without a conflict are kept
+        assert_eq!(
+            ids["a66571b0-1657-41ad-a160-ba5c3b722835"],
+            "a66571b0-1657-41ad-a160-ba5c3b722835"
+        );
+
+        let imported = doc.handle(new_id).unwrap();
+        let operator = doc[imported].children("operators")[0];
+        assert_eq!(doc[operator].child("system"), Some(imported));
+        assert_eq!(
+            doc.get("3535d7f5-7d31-4b97-b772-46fadd300992")
+                .unwrap()
+                .name(),
+            Some("existing")
+        );
+
+        // hook the imported system up next to the existing one
+        let root = doc.roots()[0];
+        if let Some(Attribute::ElementArray(systems)) =
+            doc[root].attributes.get_mut("particleSystemDefinitions")
+        {
+            systems.push(ElementRef::Handle(imported));
+        }
+        assert!(doc.collect_garbage().is_empty());
+        assert_eq!(
+            doc[imported].value("name"),
+            Some(&KV2Value::String("imported".to_string()))
+        );
+    }
+
+    #[test]
+    fn imported_dangling_references_stay_dangling() {
+        let (mut doc, _) = documents();
+        let (_, other) = parse_kv2_document(
+            r#"
+"DmeParticleOperator"
+{
+    "id" "elementid" "a66571b0-1657-41ad-a160-ba5c3b722835"
+    "system" "element" "3535d7f5-7d31-4b97-b772-46fadd300992"
+}
+"#,
+        )
+        .unwrap();
+        let ids = doc.import(&other, other.roots(), MergePolicy::Reid);
+
+        // the reference pointed outside `other`, not at the local system
+        let operator = doc
+            .handle(&ids["a66571b0-1657-41ad-a160-ba5c3b722835"])
+            .unwrap();
+        assert_eq!(doc[operator].child("system"), None);
+        assert_eq!(
+            doc[operator].attributes["system"],
+            Attribute::Element(ElementRef::Dangling(
+                "3535d7f5-7d31-4b97-b772-46fadd300992".to_string()
+            ))
+        );
+    }
+}
+
+#[cfg(test)]
+mod validate_tests {
+    use crate::{
+        parse_document_with, parse_kv2_document, Attribute, DuplicateKeyPolicy, Kv2Document,
+        Location, ParseOptions, ValidationIssue, ValidationIssueKind, ValidationRules,
+    };
+
+    fn keep_all(input: &str) -> Kv2Document {
+        let options = ParseOptions {
+            duplicate_keys: DuplicateKeyPolicy::KeepAll,
+            ..Default::default()
+        };
+        parse_document_with(input, &options).unwrap()
+    }
+
+    #[test]
+    fn validate_reports_every_kind_of_issue() {
+        let input = r#"
+"DmeParticleSystemDefinition"
+{
+    "id" "elementid" "3535d7f5-7d31-4b97-b772-46fadd300992"
+    "name" "string" "default"
+    "name" "string" "merged"
+    "operators" "element_array"
+    [
+        "DmeParticleOperator"
+        {
+            "id" "elementid" "a66571b0-1657-41ad-a160-ba5c3b722835"
+        },
+        "DmeTransform"
+        {
+            "id" "elementid" "a66571b0-1657-41ad-a160-ba5c3b722835"
+        },
+        "element" "1ec8a22e-5e14-45fe-9dab-02ffdd5772c8"
+    ]
+    "renderers" "element_array"
+    [
+        "DmeParticleOperator"
+        {
+            "name" "string" "render_animated_sprites"
+        }
+    ]
+}
+"#;
+        let doc = keep_all(input);
+        let system = doc.roots()[0];
+        let issues = doc.validate();
+
+        let kinds: Vec<&ValidationIssueKind> = issues.iter().map(|issue| &issue.kind).collect();
+        assert_eq!(issues.len(), 5, "{:#?}", issues);
+        assert!(kinds.contains(&&ValidationIssueKind::DuplicateId(
+            "a66571b0-1657-41ad-a160-ba5c3b722835".to_string()
+        )));
+        assert!(kinds.contains(&&ValidationIssueKind::MissingId));
+        assert!(issues.contains(&ValidationIssue {
+            kind: ValidationIssueKind::DanglingReference(
+                "1ec8a22e-5e14-45fe-9dab-02ffdd5772c8".to_string()
+            ),
+            location: Location {
+                element: Some(system),
+                attribute: Some("operators".to_string()),
+                index: Some(2),
+                offset: input.find("\"element\" \"1ec8a22e"),
+            },
+        }));
+        assert!(issues.contains(&ValidationIssue {
+            kind: ValidationIssueKind::WrongClass {
+                found: "DmeTransform".to_string(),
+                expected: vec!["DmeParticleOperator".to_string()],
+            },
+            location: Location {
+                element: Some(system),
+                attribute: Some("operators".to_string()),
+                index: Some(1),
+                offset: input.find("\"DmeTransform\""),
+            },
+        }));
+
+        let duplicate = issues
+            .iter()
+            .find(|issue| issue.kind == ValidationIssueKind::DuplicateAttribute)
+            .expect("expected a duplicate attribute");
+        assert_eq!(duplicate.location.element, Some(system));
+        assert_eq!(duplicate.location.attribute.as_deref(), Some("name"));
+        let offset = duplicate.location.offset.unwrap();
+        assert!(input[offset..].starts_with("\"name\" \"string\" \"merged\""));
+    }
+
+    #[test]
+    fn duplicate_attributes_point_at_their_element() {
+        let input = r#"
+"DmeModel"
+{
+    "id" "elementid" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+    "transform" "DmeTransform"
+    {
+        "scale" "float" "1"
+        "scale" "float" "2"
+    }
+}
+"#;
+        let mut doc = keep_all(input);
+        let transform = doc[doc.roots()[0]].child("transform").unwrap();
+        doc.regenerate_ids();
+
+        let issues = doc.validate();
+        let duplicate = issues
+            .iter()
+            .find(|issue| issue.kind == ValidationIssueKind::DuplicateAttribute)
+            .expect("expected a duplicate attribute");
+        assert_eq!(duplicate.location.element, Some(transform));
+        assert_eq!(duplicate.location.attribute.as_deref(), Some("scale"));
+        let offset = duplicate.location.offset.unwrap();
+        assert!(input[offset..].starts_with("\"scale\" \"float\" \"2\""));
+
+        // duplicates follow edits instead of the source
+        doc[transform].repeated.clear();
+        let is_duplicate =
+            |issue: &ValidationIssue| issue.kind == ValidationIssueKind::DuplicateAttribute;
+        assert!(!doc.validate().iter().any(is_duplicate));
+
+        let added = doc.add_element("DmeTransform", "56f186a9-1316-46c1-b82d-f46d5f19e19e");
+        doc[added].repeated.push((
+            "scale".to_string(),
+            Attribute::Value(crate::KV2Value::Double(3.0)),
+        ));
+        let issues = doc.validate();
+        let duplicate = issues.iter().find(|issue| is_duplicate(issue)).unwrap();
+        assert_eq!(duplicate.location.element, Some(added));
+        assert_eq!(duplicate.location.offset, None);
+    }
+
+    #[test]
+    fn key_value_array_items_need_no_id() {
+        let input = r#"
+"DmElement"
+{
+    "id" "elementid" "df939bf4-8dd6-435c-9eef-a6e25434ecca"
+    "settings" "element_array"
+    [
+        "mode" "fast"
+    ]
+}
+"#;
+        let (_, doc) = parse_kv2_document(input).unwrap();
+        assert_eq!(doc.len(), 2);
+        assert!(doc.validate().is_empty(), "{:#?}", doc.validate());
+    }
+
+    #[test]
+    fn validate_with_custom_rules() {
+        let input = r#"
+"DmeModel"
+{
+    "id" "elementid" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+    "shape" "DmeTransform"
+    {
+        "id" "elementid" "56f186a9-1316-46c1-b82d-f46d5f19e19e"
+    }
+}
+"#;
+        let (_, doc) = parse_kv2_document(input).unwrap();
+        assert!(doc.validate().is_empty());
+
+        let rules = ValidationRules::new().expect_class("DmeModel", "shape", &["DmeMesh"]);
+        let issues = doc.validate_with(&rules);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(
+            issues[0].to_string(),
+            "reference to a DmeTransform element, expected DmeMesh (element #0, attribute \"shape\") at byte 78"
+        );
+        assert!(input[78..].starts_with("\"shape\" \"DmeTransform\""));
+    }
+}
+
+#[cfg(test)]
+mod visit_tests {
+    use crate::{
+        parse_kv2, parse_kv2_document, visit_objects, KV2Object, KV2Value, Kv2Path, Kv2Visitor,
+    };
+
+    const INPUT: &str = r#"
+"DmeModel"
+{
+    "id" "elementid" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+    "children" "element_array"
+    [
+        "DmeJoint"
+        {
+            "id" "elementid" "a6d7e5f8-ba52-4c81-9bdf-4b0fb6892de9"
+            "transform" "DmeTransform"
+            {
+                "id" "elementid" "56f186a9-1316-46c1-b82d-f46d5f19e19e"
+                "position" "vector3" "0 0 0"
+            }
+        }
+    ]
+    "jointList" "element_array"
+    [
+        "element" "a6d7e5f8-ba52-4c81-9bdf-4b0fb6892de9"
+    ]
+    "root" "element" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+}
+"#;
+
+    #[derive(Default)]
+    struct Recorder(Vec<String>);
+
+    impl Kv2Visitor for Recorder {
+        fn enter_element(&mut self, path: &Kv2Path, object: &KV2Object) {
+            self.0.push(format!("enter {} {}", object.class_name, path));
+        }
+
+        fn leave_element(&mut self, path: &Kv2Path, object: &KV2Object) {
+            self.0.push(format!("leave {} {}", object.class_name, path));
+        }
+
+        fn attribute(&mut self, path: &Kv2Path, name: &str, _value: &KV2Value) {
+            if name != "id" {
+                self.0.push(format!("attribute {}", path));
+            }
+        }
+
+        fn array_item(&mut self, path: &Kv2Path, index: usize, _value: &KV2Value) {
+            self.0.push(format!("item {} {}", index, path));
+        }
+    }
+
+    #[test]
+    fn visitor_sees_elements_attributes_and_items_in_order() {
+        let (_, objects) = parse_kv2(INPUT).unwrap();
+        let mut recorder = Recorder::default();
+        visit_objects(&mut recorder, &objects);
+
+        assert_eq!(
+            recorder.0,
+            vec![
+                "enter DmeModel [0]",
+                "attribute [0]/children",
+                "item 0 [0]/children[0]",
+                "enter DmeJoint [0]/children[0]",
+                "attribute [0]/children[0]/transform",
+                "enter DmeTransform [0]/children[0]/transform",
+                "attribute [0]/children[0]/transform/position",
+                "leave DmeTransform [0]/children[0]/transform",
+                "leave DmeJoint [0]/children[0]",
+                "attribute [0]/jointList",
+                "item 0 [0]/jointList[0]",
+                "attribute [0]/root",
+                "leave DmeModel [0]",
+            ]
+        );
+    }
+
+    #[test]
+    fn walk_visits_shared_elements_once() {
+        let (_, doc) = parse_kv2_document(INPUT).unwrap();
+        let walked: Vec<(String, String, usize)> = doc
+            .walk()
+            .map(|entry| {
+                (
+                    entry.element.class_name.clone(),
+                    entry.path.to_string(),
+                    entry.depth,
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            walked,
+            vec![
+                ("DmeModel".to_string(), "[0]".to_string(), 0),
+                ("DmeJoint".to_string(), "[0]/children[0]".to_string(), 1),
+                (
+                    "DmeTransform".to_string(),
+                    "[0]/children[0]/transform".to_string(),
+                    2
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn repeated_attributes_are_visited_walked_and_selected() {
+        let input = r#"
+"DmeModel"
+{
+    "id" "elementid" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+    "child" "DmeDag" { "id" "elementid" "a6d7e5f8-ba52-4c81-9bdf-4b0fb6892de9" }
+    "name" "string" "first"
+    "child" "DmeDag" { "id" "elementid" "b4115142-4f81-4569-8c9a-3bdcded9b36f" }
+    "name" "string" "second"
+}
+"#;
+        let options = crate::ParseOptions {
+            duplicate_keys: crate::DuplicateKeyPolicy::KeepAll,
+            ..Default::default()
+        };
+
+        let (_, objects) = crate::parse_kv2_with(input, &options).unwrap();
+        let mut recorder = Recorder::default();
+        visit_objects(&mut recorder, &objects);
+        assert_eq!(
+            recorder.0,
+            vec![
+                "enter DmeModel [0]",
+                "attribute [0]/child",
+                "enter DmeDag [0]/child",
+                "leave DmeDag [0]/child",
+                "attribute [0]/name",
+                "attribute [0]/child#1",
+                "enter DmeDag [0]/child#1",
+                "leave DmeDag [0]/child#1",
+                "attribute [0]/name#1",
+                "leave DmeModel [0]",
+            ]
+        );
+
+        let mut doc = crate::parse_document_with(input, &options).unwrap();
+        let walked: Vec<String> = doc.walk().map(|entry| entry.path.to_string()).collect();
+        assert_eq!(walked, vec!["[0]", "[0]/child", "[0]/child#1"]);
+
+        let names = doc.select("DmeModel/name").unwrap();
+        assert_eq!(names.len(), 2);
+        assert_eq!(doc.select("DmeModel/child").unwrap().len(), 2);
+
+        // visit_mut offers repeated values too and can drop them
+        struct DropSecondName(Vec<String>);
+        impl crate::Kv2VisitorMut for DropSecondName {
+            fn keep_attribute(
+                &mut self,
+                path: &Kv2Path,
+                _name: &str,
+                _attribute: crate::VisitedAttribute<'_>,
+            ) -> bool {
+                self.0.push(path.to_string());
+                path.to_string() != "[0]/name#1"
+            }
+        }
+        let mut visitor = DropSecondName(Vec::new());
+        doc.visit_mut(&mut visitor);
+        assert!(visitor.0.contains(&"[0]/child#1".to_string()));
+        let model = doc.roots()[0];
+        assert_eq!(doc[model].repeated.len(), 1);
+        assert_eq!(doc[model].repeated[0].0, "child");
+        let (_, mut objects) = crate::parse_kv2_with(input, &options).unwrap();
+        crate::visit_objects_mut(&mut DropSecondName(Vec::new()), &mut objects);
+        assert_eq!(objects[0].repeated.len(), 1);
+        assert_eq!(objects[0].repeated[0].0, "child");
+    }
+}
+
+#[cfg(test)]
+mod visit_mut_tests {
+    use crate::{
+        parse_kv2, parse_kv2_document, visit_objects_mut, KV2Value, Kv2Path, Kv2VisitorMut,
+        VisitedAttribute,
+    };
+
+    const INPUT: &str = r#"
+"DmeModel"
+{
+    "id" "elementid" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+    "transform" "DmeTransform"
+    {
+        "id" "elementid" "56f186a9-1316-46c1-b82d-f46d5f19e19e"
+        "position" "vector3" "1 2 3"
+        "operator start fadein" "float" "0"
+        "operator start fadeout" "float" "0.5"
+    }
+    "children" "element_array"
+    [
+        "DmeDag"
+        {
+            "id" "elementid" "a6d7e5f8-ba52-4c81-9bdf-4b0fb6892de9"
+            "transform" "element" "56f186a9-1316-46c1-b82d-f46d5f19e19e"
+            "operator end fadein" "float" "0"
+        }
+    ]
+}
+"#;
+
+    /// Scales positions, renames DmeDag to DmeJoint and drops operator fade
+    /// attributes that are zero
+    struct BulkEdit {
+        scaled: usize,
+    }
+
+    impl Kv2VisitorMut for BulkEdit {
+        fn element(&mut self, _path: &Kv2Path, class_name: &mut String) {
+            if class_name == "DmeDag" {
+                *class_name = "DmeJoint".to_string();
+            }
+        }
+
+        fn keep_attribute(
+            &mut self,
+            _path: &Kv2Path,
+            name: &str,
+            attribute: VisitedAttribute<'_>,
+        ) -> bool {
+            !name.starts_with("operator ") || attribute.value() != Some(&KV2Value::Double(0.0))
+        }
+
+        fn value(&mut self, _path: &Kv2Path, name: &str, value: &mut KV2Value) {
+            if let (true, KV2Value::Vector(v)) = (name == "position", value) {
+                v.iter_mut().for_each(|c| *c *= 2.0);
+                self.scaled += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn visit_mut_edits_trees() {
+        let (_, mut objects) = parse_kv2(INPUT).unwrap();
+        let mut edit = BulkEdit { scaled: 0 };
+        visit_objects_mut(&mut edit, &mut objects);
+
+        assert_eq!(edit.scaled, 1);
+        let transform = match &objects[0].fields["transform"] {
+            KV2Value::Object(transform) => transform,
+            other => panic!("expected an object, got {:?}", other),
+        };
+        assert_eq!(
+            transform.fields["position"],
+            KV2Value::Vector(vec![2.0, 4.0, 6.0])
+        );
+        assert!(!transform.fields.contains_key("operator start fadein"));
+        assert!(transform.fields.contains_key("operator start fadeout"));
+        match &objects[0].fields["children"] {
+            KV2Value::Array(items) => match &items[0] {
+                KV2Value::Object(child) => {
+                    assert_eq!(child.class_name, "DmeJoint");
+                    assert!(!child.fields.contains_key("operator end fadein"));
+                }
+                other => panic!("expected an object, got {:?}", other),
+            },
+            other => panic!("expected an array, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn visit_mut_edits_shared_document_elements_once() {
+        let (_, mut doc) = parse_kv2_document(INPUT).unwrap();
+        let mut edit = BulkEdit { scaled: 0 };
+        doc.visit_mut(&mut edit);
+
+        // the transform is shared by the model and the dag but scaled once
+        assert_eq!(edit.scaled, 1);
+        let transform = doc.get("56f186a9-1316-46c1-b82d-f46d5f19e19e").unwrap();
+        assert_eq!(
+            transform.value("position"),
+            Some(&KV2Value::Vector(vec![2.0, 4.0, 6.0]))
+        );
+        assert!(transform.get("operator start fadein").is_none());
+        assert!(transform.get("operator start fadeout").is_some());
+
+        let dag = doc.get("a6d7e5f8-ba52-4c81-9bdf-4b0fb6892de9").unwrap();
+        assert_eq!(dag.class_name, "DmeJoint");
+        assert!(dag.get("operator end fadein").is_none());
+        assert!(dag.child("transform").is_some());
+    }
+}
+
+#[cfg(test)]
+mod query_tests {
+    use crate::{parse_kv2_document, KV2Value, Selection};
+
+    const INPUT: &str = r#"
+"DmeModel"
+{
+    "id" "elementid" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+    "children" "element_array"
+    [
+        "DmeDag"
+        {
+            "id" "elementid" "a6d7e5f8-ba52-4c81-9bdf-4b0fb6892de9"
+            "transform" "DmeTransform"
+            {
+                "id" "elementid" "56f186a9-1316-46c1-b82d-f46d5f19e19e"
+                "position" "vector3" "1 2 3"
+            }
+        },
+        "DmeDag"
+        {
+            "id" "elementid" "b4115142-4f81-4569-8c9a-3bdcded9b36f"
+            "transform" "DmeTransform"
+            {
+                "id" "elementid" "9eac606c-1fc5-474f-b17f-9fc503b8a7ae"
+                "position" "vector3" "4 5 6"
+            }
+        }
+    ]
+}
+
+"DmeParticleSystemDefinition"
+{
+    "id" "elementid" "3535d7f5-7d31-4b97-b772-46fadd300992"
+    "operators" "element_array"
+    [
+        "DmeParticleOperator"
+        {
+            "id" "elementid" "a66571b0-1657-41ad-a160-ba5c3b722835"
+            "functionName" "string" "alpha_fade"
+            "end_alpha" "float" "0"
+            "operator end fadein" "float" "0.5"
+        },
+        "DmeParticleOperator"
+        {
+            "id" "elementid" "1ec8a22e-5e14-45fe-9dab-02ffdd5772c8"
+            "functionName" "string" "basic_movement"
+            "operator end fadein" "float" "0.25"
+        }
+    ]
+}
+"#;
+
+    fn values<'a>(selected: &[Selection<'a>]) -> Vec<&'a KV2Value> {
+        selected
+            .iter()
+            .map(|selection| match selection {
+                Selection::Value { value, .. } => *value,
+                other => panic!("expected a value, got {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn select_by_attribute_path() {
+        let (_, doc) = parse_kv2_document(INPUT).unwrap();
+
+        let selected = doc
+            .select("DmeModel/children[1]/transform/position")
+            .unwrap();
+        assert_eq!(
+            values(&selected),
+            vec![&KV2Value::Vector(vec![4.0, 5.0, 6.0])]
+        );
+
+        let selected = doc.select("DmeModel/children/transform/position").unwrap();
+        assert_eq!(selected.len(), 2);
+
+        let transform = doc.handle("56f186a9-1316-46c1-b82d-f46d5f19e19e").unwrap();
+        let selected = doc.select("/DmeModel/children[0]/transform").unwrap();
+        assert_eq!(selected, vec![Selection::Element(transform)]);
+
+        assert!(doc
+            .select("DmeParticleSystemDefinition/children")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn select_descendants_with_predicates() {
+        let (_, doc) = parse_kv2_document(INPUT).unwrap();
+
+        let selected = doc
+            .select(r#"//DmeParticleOperator[functionName="alpha_fade"]/end_alpha"#)
+            .unwrap();
+        assert_eq!(values(&selected), vec![&KV2Value::Double(0.0)]);
+
+        let selected = doc
+            .select(r#"//DmeParticleOperator/"operator end fadein""#)
+            .unwrap();
+        assert_eq!(
+            values(&selected),
+            vec![&KV2Value::Double(0.5), &KV2Value::Double(0.25)]
+        );
+
+        let selected = doc.select("DmeModel//DmeTransform").unwrap();
+        assert_eq!(selected.len(), 2);
+        assert_eq!(doc.select("//*").unwrap().len(), 8);
+        assert_eq!(doc.select("//DmeDag[1]").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn select_rejects_invalid_paths() {
+        let (_, doc) = parse_kv2_document(INPUT).unwrap();
+
+        let error = doc.select("DmeModel/children[0").unwrap_err();
+        assert_eq!(error.offset, 17);
+        assert!(doc.select("").is_err());
+    }
+
+    #[test]
+    fn select_value_array_items_and_quoted_names() {
+        let input = r#"
+"DmeModel"
+{
+    "id" "elementid" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+    "vals" "float_array" [ "0.5", "1.5" ]
+    "say \"hi\"" "string" "hello"
+}
+"#;
+        let (_, doc) = parse_kv2_document(input).unwrap();
+
+        let selected = doc.select("DmeModel/vals[1]").unwrap();
+        assert_eq!(values(&selected), vec![&KV2Value::Double(1.5)]);
+        assert!(doc.select("DmeModel/vals[2]").unwrap().is_empty());
+
+        // whitespace around unquoted names doesn't count
+        let selected = doc.select(" DmeModel / vals [0] ").unwrap();
+        assert_eq!(values(&selected), vec![&KV2Value::Double(0.5)]);
+
+        let selected = doc.select(r#"DmeModel/"say \"hi\"""#).unwrap();
+        assert_eq!(
+            values(&selected),
+            vec![&KV2Value::String("hello".to_string())]
+        );
+    }
+}
+
+#[cfg(test)]
+mod referrers_tests {
+    use crate::{parse_kv2_document, Attribute, ElementRef, KV2Value, Kv2Document, Referrer};
+
+    const INPUT: &str = r#"
+"DmeModel"
+{
+"id" "elementid" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+"transform" "DmeTransform"
+{
+    "id" "elementid" "56f186a9-1316-46c1-b82d-f46d5f19e19e"
+}
+"children" "element_array"
+[
+    "DmeDag"
+    {
+        "id" "elementid" "aa5a3c8d-1a23-4b46-a8d4-1dbd38bb1f5b"
+        "transform" "element" "56f186a9-1316-46c1-b82d-f46d5f19e19e"
+    },
+    "element" "56f186a9-1316-46c1-b82d-f46d5f19e19e"
+]
+}
+"#;
+
+    #[test]
+    fn referrers_lists_every_reference() {
+        let (_, doc) = parse_kv2_document(INPUT).unwrap();
+        let model = doc.roots()[0];
+        let dag = doc.handle("aa5a3c8d-1a23-4b46-a8d4-1dbd38bb1f5b").unwrap();
+
+        let referrers = doc.referrers("56f186a9-1316-46c1-b82d-f46d5f19e19e");
+        assert_eq!(
+            referrers,
+            vec![
+                Referrer {
+                    element: model,
+                    attribute: "children".to_string(),
+                    index: Some(1),
+                },
+                Referrer {
+                    element: model,
+                    attribute: "transform".to_string(),
+                    index: None,
+                },
+                Referrer {
+                    element: dag,
+                    attribute: "transform".to_string(),
+                    index: None,
+                },
+            ]
+        );
+        assert_eq!(doc.referrers_of(dag).len(), 1);
+        assert!(doc.referrers_of(model).is_empty());
+        assert!(doc.referrers("unknown").is_empty());
+    }
+
+    #[test]
+    fn referrers_follow_edits() {
+        let (_, mut doc) = parse_kv2_document(INPUT).unwrap();
+        let transform_id = "56f186a9-1316-46c1-b82d-f46d5f19e19e";
+        let model = doc.roots()[0];
+        assert_eq!(doc.referrers(transform_id).len(), 3);
+
+        doc[model].remove("transform");
+        assert_eq!(doc.referrers(transform_id).len(), 2);
+
+        // Removed elements are still found through the dangling references
+        let transform = doc.handle(transform_id).unwrap();
+        doc.remove_element(transform);
+        assert_eq!(doc.referrers(transform_id).len(), 2);
+
+        let added = doc.add_element("DmeTransform", transform_id);
+        assert_eq!(doc.referrers_of(added).len(), 2);
+
+        doc.set_id(added, "e3bf2c6d-0d83-4c6e-9d8c-2b3d0c5a7f10");
+        assert_eq!(
+            doc.referrers("e3bf2c6d-0d83-4c6e-9d8c-2b3d0c5a7f10").len(),
+            2
+        );
+        assert!(doc.referrers(transform_id).is_empty());
+
+        doc[model].set("transform", Attribute::Element(ElementRef::Handle(added)));
+        assert_eq!(doc.referrers_of(added).len(), 3);
+    }
+
+    /// Checks the referrers of every element against a scan of the document
+    fn assert_referrers_current(doc: &Kv2Document) {
+        for (target, _) in doc.elements() {
+            let mut expected: Vec<Referrer> = doc
+                .elements()
+                .flat_map(|(handle, element)| {
+                    element
+                        .references()
+                        .filter(|(_, _, to)| to.handle() == Some(target))
+                        .map(move |(attribute, index, _)| Referrer {
+                            element: handle,
+                            attribute: attribute.to_string(),
+                            index,
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+            expected.sort();
+            assert_eq!(doc.referrers_of(target), expected);
+        }
+    }
+
+    #[test]
+    fn referrers_interleaved_with_edits() {
+        let (_, mut doc) = parse_kv2_document(INPUT).unwrap();
+        let transform_id = "56f186a9-1316-46c1-b82d-f46d5f19e19e";
+        let model = doc.roots()[0];
+        let dag = doc.handle("aa5a3c8d-1a23-4b46-a8d4-1dbd38bb1f5b").unwrap();
+        assert_referrers_current(&doc);
+
+        doc[dag].class_name = "DmeJoint".to_string();
+        doc[dag].set_value("visible", KV2Value::Bool(true));
+        assert_referrers_current(&doc);
+
+        doc[dag].set("parent", Attribute::Element(ElementRef::Handle(model)));
+        assert_referrers_current(&doc);
+        assert_eq!(doc.referrers_of(model).len(), 1);
+
+        doc[model].remove("children");
+        assert_referrers_current(&doc);
+
+        let transform = doc.handle(transform_id).unwrap();
+        doc.remove_element(transform);
+        assert_referrers_current(&doc);
+        assert_eq!(doc.referrers(transform_id).len(), 2);
+
+        let added = doc.add_element("DmeTransform", transform_id);
+        assert_referrers_current(&doc);
+        assert_eq!(doc.referrers_of(added).len(), 2);
+
+        doc.collect_garbage();
+        assert_referrers_current(&doc);
+    }
+}
+
+#[cfg(test)]
+mod dot_tests {
+    use crate::{parse_kv2_document, DotOptions};
+
+    const INPUT: &str = r#"
+"DmeModel"
+{
+"id" "elementid" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+"name" "string" "body"
+"visible" "bool" "1"
+"children" "element_array"
+[
+    "DmeDag"
+    {
+        "id" "elementid" "aa5a3c8d-1a23-4b46-a8d4-1dbd38bb1f5b"
+        "transform" "DmeTransform"
+        {
+            "id" "elementid" "56f186a9-1316-46c1-b82d-f46d5f19e19e"
+        }
+    },
+    "element" "df939bf4-8dd6-435c-9eef-a6e25434ecca"
+]
+}
+"#;
+
+    #[test]
+    fn to_dot_writes_nodes_and_edges() {
+        let (_, doc) = parse_kv2_document(INPUT).unwrap();
+        let dot = doc.to_dot();
+
+        assert!(dot.starts_with("digraph kv2 {\n"));
+        assert!(dot.contains(r#"e0 [label="DmeModel\n\"body\"\nvisible = true"];"#));
+        assert!(dot.contains(r#"e1 [label="DmeDag"];"#));
+        assert!(dot.contains(r#"e0 -> e1 [label="children[0]"];"#));
+        assert!(dot.contains(r#"e1 -> e2 [label="transform"];"#));
+        assert!(
+            dot.contains(r#"e0 -> "df939bf4-8dd6-435c-9eef-a6e25434ecca" [label="children[1]"];"#)
+        );
+        assert!(dot.contains(r#""df939bf4-8dd6-435c-9eef-a6e25434ecca" [style=dashed];"#));
+    }
+
+    #[test]
+    fn to_dot_collapses_values_and_limits_depth() {
+        let (_, doc) = parse_kv2_document(INPUT).unwrap();
+        let dot = doc.to_dot_with(&DotOptions {
+            collapse_values: true,
+            max_depth: Some(1),
+            ..Default::default()
+        });
+
+        assert!(dot.contains(r#"e0 [label="DmeModel\n\"body\""];"#));
+        assert!(dot.contains("e0 -> e1"));
+        assert!(!dot.contains("e2 ["));
+
+        let dag = doc.handle("aa5a3c8d-1a23-4b46-a8d4-1dbd38bb1f5b").unwrap();
+        let dot = doc.to_dot_with(&DotOptions {
+            roots: Some(vec![dag]),
+            ..Default::default()
+        });
+        assert!(!dot.contains("e0 ["));
+        assert!(dot.contains("e1 -> e2"));
+    }
+}
+
+#[cfg(test)]
+mod error_tests {
+    use crate::{parse_kv2, parse_kv2_document, Expected};
+
+    #[test]
+    fn error_points_into_the_broken_array() {
+        let input = r#"
+"DmeModel"
+{
+"id" "elementid" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+"children" "element_array"
+[
+    "DmeDag"
+    {
+        "id" "elementid" "aa5a3c8d-1a23-4b46-a8d4-1dbd38bb1f5b"
+    },
+    "DmeDag"
+    {
+        "name" "string" "broken
+    }
+]
+}
+"#;
+        let error = parse_kv2(input).unwrap_err();
+        assert_eq!(error.path.to_string(), "[0]/children[1]/name");
+        assert_eq!((error.line, error.column), (13, 25));
+        assert_eq!(error.offset, input.find("\"broken").unwrap());
+        assert!(error.expected.contains(&Expected::QuotedString));
+
+        // A missing closing brace is reported where the attributes end
+        let input = input
+            .replace("\"broken", "\"fixed\"")
+            .replace("    }\n]", "]");
+        let error = parse_kv2(&input).unwrap_err();
+        assert_eq!(error.path.to_string(), "[0]/children[1]");
+        assert_eq!((error.line, error.column), (14, 1));
+        assert_eq!(
+            error.to_string(),
+            r#"14:1: expected a quoted string or "}" in [0]/children[1]"#
+        );
+    }
+
+    #[test]
+    fn error_lists_every_expectation() {
+        let input = "\"DmElement\"\n{\n\"name\" \"string\" root\n";
+        let error = parse_kv2_document(input).unwrap_err();
+        assert_eq!((error.line, error.column), (3, 17));
+        assert_eq!(
+            error.expected,
+            vec![Expected::QuotedString, Expected::Token("{")]
+        );
+        assert_eq!(
+            error.to_string(),
+            r#"3:17: expected a quoted string or "{" in [0]/name"#
+        );
+
+        let error = parse_kv2("\"DmElement\"\n{\n").unwrap_err();
+        assert_eq!((error.line, error.column), (3, 1));
+        assert!(error.expected.contains(&Expected::Token("}")));
+    }
+}
+
+#[cfg(test)]
+mod diagnostic_tests {
+    use crate::{
+        parse_document_with, parse_kv2, Diagnostic, DuplicateKeyPolicy, ParseOptions, Severity,
+    };
+
+    #[test]
+    fn render_expands_tabs_and_handles_end_of_input() {
+        let input =
+            "\"DmElement\"\n{\n\t\"children\" \"element_array\"\n\t[\n\t\t\"DmeDag\" oops\n";
+        let error = parse_kv2(input).unwrap_err();
+        let diagnostic = Diagnostic::from_error(&error, input);
+        assert_eq!(diagnostic.severity, Severity::Error);
+        assert_eq!(
+            diagnostic.render(input, "model.dmx"),
+            r#"error: expected "{" or a quoted string, found `oops`
+ --> model.dmx:5:12
+  |
+5 |         "DmeDag" oops
+  |                  ^^^^ expected "{" or a quoted string
+  |
+  = note: in [0]/children[0]
+"#
+        );
+
+        let input = "\"DmElement\"\n{\n";
+        let error = parse_kv2(input).unwrap_err();
+        let rendered = Diagnostic::from_error(&error, input).render(input, "model.dmx");
+        assert!(
+            rendered.starts_with("error: expected a quoted string or \"}\", found end of input\n")
+        );
+        assert!(rendered.contains("3 | \n  | ^ expected"));
+    }
+
+    #[test]
+    fn diagnostics_as_json() {
+        let input = "\"DmElement\"\n{\n\"name\" \"string\" root\n";
+        let error = parse_kv2(input).unwrap_err();
+        assert_eq!(
+            Diagnostic::from_error(&error, input).to_json(),
+            r#"{"severity":"error","message":"expected a quoted string or \"{\", found `root`","label":"expected a quoted string or \"{\"","start":30,"end":34,"line":3,"column":17,"notes":["in [0]/name"]}"#
+        );
+
+        let input = "\"DmElement\"\n{\n\"name\" \"string\" \"a\"\n\"name\" \"string\" \"b\"\n}\n";
+        let options = ParseOptions {
+            duplicate_keys: DuplicateKeyPolicy::KeepAll,
+            ..Default::default()
+        };
+        let doc = parse_document_with(input, &options).unwrap();
+        let issues = doc.validate();
+        let diagnostic = Diagnostic::from_issue(&issues[1], input).unwrap();
+        assert_eq!(diagnostic.severity, Severity::Warning);
+        assert_eq!((diagnostic.line, diagnostic.column), (4, 1));
+        assert_eq!(&input[diagnostic.span], "\"name\"");
+    }
+}
+
+#[cfg(test)]
+mod recovery_tests {
+    use crate::{parse_kv2_document, parse_kv2_document_recovering, Expected, KV2Value};
+
+    const INPUT: &str = r#"<!-- dmx encoding keyvalues2 1 format model 1 -->
+"DmeModel"
+{
+    "id" "elementid" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+    "name" "string" body
+    "children" "element_array"
+    [
+        "DmeDag"
+        {
+            "id" "elementid" "aa5a3c8d-1a23-4b46-a8d4-1dbd38bb1f5b"
+            "visible" "bool"
+        },
+        "DmeDag" oops
+    ]
+    "visible" "bool" "1"
+}
+garbage
+"DmElement"
+{
+    "id" "elementid" "df939bf4-8dd6-435c-9eef-a6e25434ecca"
+    "name" "string" "last"
+"#;
+
+    #[test]
+    fn recovery_reports_every_error() {
+        assert!(parse_kv2_document(INPUT).is_err());
+
+        let (_, errors) = parse_kv2_document_recovering(INPUT);
+        let found: Vec<(usize, String)> = errors
+            .iter()
+            .map(|error| (error.line, error.path.to_string()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (5, "[0]/name".to_string()),
+                (12, "[0]/children[0]/visible".to_string()),
+                (13, "[0]/children[1]".to_string()),
+                (17, "[1]".to_string()),
+                (22, "[2]".to_string()),
+            ]
+        );
+        assert!(errors[4].expected.contains(&Expected::Token("}")));
+    }
+
+    #[test]
+    fn recovery_keeps_what_could_be_parsed() {
+        let (doc, _) = parse_kv2_document_recovering(INPUT);
+        assert_eq!(doc.header().unwrap().format, "model");
+        assert_eq!(doc.roots().len(), 2);
+
+        let model = &doc[doc.roots()[0]];
+        assert!(model.value("name").is_none());
+        assert!(model.get("children").is_none());
+        assert_eq!(model.value("visible"), Some(&KV2Value::Bool(true)));
+
+        // The broken array is skipped as a whole, with the items before the
+        // error
+        assert!(!doc.contains("aa5a3c8d-1a23-4b46-a8d4-1dbd38bb1f5b"));
+
+        let last = doc.get("df939bf4-8dd6-435c-9eef-a6e25434ecca").unwrap();
+        assert_eq!(last.name(), Some("last"));
+    }
+}
+
+#[cfg(test)]
+mod complete_input_tests {
+    use crate::{parse_document, parse_kv2, Expected};
+
+    #[test]
+    fn parse_document_requires_all_input() {
+        let input = r#"
+"DmElement"
+{
+"id" "elementid" "df939bf4-8dd6-435c-9eef-a6e25434ecca"
+}
+<!-- trailing comment -->
+"#;
+        let doc = parse_document(input).unwrap();
+        assert_eq!(doc.roots().len(), 1);
+
+        let input = format!("{}\"DmeModel\" oops\n", input);
+        let (rest, objects) = parse_kv2(&input).unwrap();
+        assert_eq!(objects.len(), 1);
+        assert!(rest.starts_with("\"DmeModel\""));
+
+        let error = parse_document(&input).unwrap_err();
+        assert_eq!((error.line, error.column), (7, 12));
+        assert_eq!(error.path.to_string(), "[1]");
+        assert_eq!(error.expected, vec![Expected::Token("{")]);
+
+        let error = parse_document("\"DmElement\" {}\n}").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 1));
+        assert_eq!(
+            error.expected,
+            vec![Expected::QuotedString, Expected::EndOfInput]
+        );
+    }
+}
+
+#[cfg(test)]
+mod escape_tests {
+    use crate::{parse_kv2, write_kv2, KV2Value, WriterOptions};
+
+    #[test]
+    fn quoted_strings_are_unescaped() {
+        let input = r#"
+"DmElement"
+{
+"description" "string" "say \"hi\"\n\tthen \\ leave"
+"path" "string" "materials\models\body.vmt"
+}
+"#;
+        let (_, objects) = parse_kv2(input).unwrap();
+        assert_eq!(
+            objects[0].fields["description"],
+            KV2Value::String("say \"hi\"\n\tthen \\ leave".to_string())
+        );
+        assert_eq!(
+            objects[0].fields["path"],
+            KV2Value::String("materials\\models\\body.vmt".to_string())
+        );
+    }
+
+    #[test]
+    fn escaped_strings_round_trip() {
+        let input = r#"
+"DmElement"
+{
+"description" "string" "a \"quoted\" \\ path\twith\nlines"
+"path" "string" "materials\models\body.vmt"
+"names" "string_array" [ "one \"1\"", "two\\" ]
+}
+"#;
+        let (_, objects) = parse_kv2(input).unwrap();
+        let output = write_kv2(&objects, &WriterOptions::default());
+        assert!(output.contains(r#""materials\\models\\body.vmt""#));
+
+        let (rest, reparsed) = parse_kv2(&output).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(reparsed, objects);
+    }
+}
+
+#[cfg(test)]
+mod encoding_tests {
+    use crate::{parse_kv2_bytes, parse_kv2_bytes_as, BytesError, Encoding, KV2Value};
+
+    const INPUT: &str = "\"DmElement\"\n{\n\"name\" \"string\" \"caf\u{e9} \u{20ac}5\"\n}\n";
+
+    #[test]
+    fn parse_bytes_in_every_encoding() {
+        let expected = KV2Value::String("caf\u{e9} \u{20ac}5".to_string());
+
+        let mut utf8 = b"\xef\xbb\xbf".to_vec();
+        utf8.extend_from_slice(INPUT.as_bytes());
+        assert_eq!(Encoding::detect(&utf8), Encoding::Utf8);
+        assert_eq!(parse_kv2_bytes(&utf8).unwrap()[0].fields["name"], expected);
+
+        let mut utf16 = b"\xff\xfe".to_vec();
+        utf16.extend(INPUT.encode_utf16().flat_map(u16::to_le_bytes));
+        assert_eq!(parse_kv2_bytes(&utf16).unwrap()[0].fields["name"], expected);
+
+        let windows_1252 = b"\"DmElement\"\n{\n\"name\" \"string\" \"caf\xe9 \x805\"\n}\n";
+        assert_eq!(Encoding::detect(windows_1252), Encoding::Windows1252);
+        assert_eq!(
+            parse_kv2_bytes(windows_1252).unwrap()[0].fields["name"],
+            expected
+        );
+    }
+
+    #[test]
+    fn parse_bytes_reports_byte_offsets() {
+        let windows_1252 =
+            b"\"DmElement\"\n{\n\"name\" \"string\" \"caf\xe9\"\n\"x\" \"int\" 1\n}\n";
+        match parse_kv2_bytes_as(windows_1252, Encoding::Utf8) {
+            Err(BytesError::Decode(error)) => {
+                assert_eq!(
+                    Some(error.offset),
+                    windows_1252.iter().position(|b| *b == 0xe9)
+                );
+                assert_eq!(error.len, 1);
+            }
+            other => panic!("expected a decode error, got {:?}", other),
+        }
+
+        // The parse error offset counts the single byte of "é"
+        match parse_kv2_bytes(windows_1252) {
+            Err(BytesError::Parse(error)) => {
+                assert_eq!(error.offset, windows_1252.len() - 4);
+                assert_eq!((error.line, error.column), (4, 11));
+            }
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+
+        match parse_kv2_bytes_as(b"\"a\" \x81", Encoding::Windows1252) {
+            Err(BytesError::Decode(error)) => assert_eq!(error.offset, 4),
+            other => panic!("expected a decode error, got {:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod reader_tests {
+    use std::io::{BufReader, Cursor};
+
+    use crate::{from_reader, parse_kv2, ReadError};
+
+    const INPUT: &str = r#"<!-- dmx encoding keyvalues2 1 format dmx 22 -->
+"DmElement"
+{
+    "id" "elementid" "df939bf4-8dd6-435c-9eef-a6e25434ecca"
+    "name" "string" "say \"hi\"\n"
+    "values" "int_array" [ "1", "2", "3" ]
+    "child" "DmeDag"
+    {
+        "name" "string" "caf\u{e9}"
+    }
+    "children" "element_array"
+    [
+        "element" "df939bf4-8dd6-435c-9eef-a6e25434ecca",
+        "DmElement" { "name" "string" "inline" }
+    ]
+}
+"#;
+
+    #[test]
+    fn from_reader_matches_parse_kv2() {
+        let mut bytes = b"\xef\xbb\xbf".to_vec();
+        bytes.extend_from_slice(INPUT.as_bytes());
+        // A tiny buffer makes tokens straddle refills
+        let reader = BufReader::with_capacity(4, Cursor::new(bytes));
+
+        let (_, expected) = parse_kv2(INPUT).unwrap();
+        assert_eq!(from_reader(reader).unwrap(), expected);
+    }
+
+    #[test]
+    fn from_reader_reports_position_and_path() {
+        let input = "\"DmElement\"\n{\n\"values\" \"int_array\" [ \"1\" \"2\" ]\n}\n";
+        match from_reader(Cursor::new(input)) {
+            Err(ReadError::Parse(error)) => {
+                assert_eq!(error, parse_kv2(input).unwrap_err());
+                assert_eq!((error.line, error.column), (3, 28));
+                assert_eq!(error.path.to_string(), "[0]/values");
+            }
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+
+        match from_reader(Cursor::new(
+            b"\"a\" { \"b\" \"string\" \"\\\"\xff\" }".to_vec(),
+        )) {
+            Err(ReadError::Decode(error)) => assert_eq!(error.offset, 22),
+            other => panic!("expected a decode error, got {:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod event_tests {
+    use crate::{Kv2Event, Kv2Events, ReadError};
+
+    fn start(key: Option<&str>, class: &str) -> Kv2Event {
+        Kv2Event::StartElement {
+            key: key.map(str::to_string),
+            class: class.to_string(),
+        }
+    }
+
+    fn item(key: Option<&str>, raw: &str) -> Kv2Event {
+        Kv2Event::Item {
+            key: key.map(str::to_string),
+            raw: raw.to_string(),
+        }
+    }
+
+    #[test]
+    fn events_follow_document_order() {
+        let input = r#"
+"DmElement"
+{
+    "name" "string" "root"
+    "child" "DmeDag" { }
+    "values" "int_array" [ "1", "2" ]
+    "children" "element_array"
+    [
+        "element" "df939bf4-8dd6-435c-9eef-a6e25434ecca",
+        "DmElement" { }
+    ]
+}
+"DmElement" { }
+"#;
+        let events: Vec<Kv2Event> = Kv2Events::new(input.as_bytes())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            events,
+            [
+                start(None, "DmElement"),
+                Kv2Event::Attribute {
+                    key: "name".to_string(),
+                    data_type: "string".to_string(),
+                    raw: "root".to_string(),
+                },
+                start(Some("child"), "DmeDag"),
+                Kv2Event::EndElement,
+                Kv2Event::StartArray {
+                    key: "values".to_string(),
+                    data_type: "int_array".to_string(),
+                },
+                item(None, "1"),
+                item(None, "2"),
+                Kv2Event::EndArray,
+                Kv2Event::StartArray {
+                    key: "children".to_string(),
+                    data_type: "element_array".to_string(),
+                },
+                item(Some("element"), "df939bf4-8dd6-435c-9eef-a6e25434ecca"),
+                start(None, "DmElement"),
+                Kv2Event::EndElement,
+                Kv2Event::EndArray,
+                Kv2Event::EndElement,
+                start(None, "DmElement"),
+                Kv2Event::EndElement,
+            ]
+        );
+    }
+
+    #[test]
+    fn events_end_after_an_error() {
+        let input = "\"DmElement\"\n{\n\"child\" \"DmeDag\" {\n\"name\" \"string\" \"a\"\n";
+        let mut events = Kv2Events::new(input.as_bytes());
+        assert_eq!(events.by_ref().take(3).filter(Result::is_ok).count(), 3);
+        match events.next() {
+            Some(Err(ReadError::Parse(error))) => {
+                assert_eq!(error.path.to_string(), "[0]/child");
+                assert_eq!(error.line, 5);
+            }
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+        assert!(events.next().is_none());
+    }
+}
+
+#[cfg(test)]
+mod borrowed_tests {
+    use std::borrow::Cow;
+
+    use crate::{parse_kv2, parse_kv2_borrowed, KV2ObjectRef, KV2ValueRef};
+
+    const INPUT: &str = r#"
+"DmElement"
+{
+    "name" "string" "say \"hi\""
+    "path" "string" "materials\models"
+    "child" "DmeDag" { "name" "string" "child" }
+    "children" "element_array" [ "element" "df939bf4-8dd6-435c-9eef-a6e25434ecca" ]
+}
+"#;
+
+    #[test]
+    fn strings_borrow_from_the_input_unless_escaped() {
+        let (_, objects) = parse_kv2_borrowed(INPUT).unwrap();
+        let root = &objects[0];
+        assert!(matches!(root.class_name, Cow::Borrowed("DmElement")));
+        assert!(root
+            .fields
+            .keys()
+            .all(|key| matches!(key, Cow::Borrowed(_))));
+
+        match &root.fields["name"] {
+            KV2ValueRef::String(Cow::Owned(name)) => assert_eq!(name, "say \"hi\""),
+            other => panic!("expected an unescaped string, got {:?}", other),
+        }
+        assert!(matches!(
+            root.fields["path"],
+            KV2ValueRef::String(Cow::Borrowed("materials\\models"))
+        ));
+        match &root.fields["child"] {
+            KV2ValueRef::Object(KV2ObjectRef { fields, .. }) => assert!(matches!(
+                fields["name"],
+                KV2ValueRef::String(Cow::Borrowed("child"))
+            )),
+            other => panic!("expected an object, got {:?}", other),
+        }
+        match &root.fields["children"] {
+            KV2ValueRef::Array(items) => assert!(matches!(
+                items[0],
+                KV2ValueRef::Element(Cow::Borrowed("df939bf4-8dd6-435c-9eef-a6e25434ecca"))
+            )),
+            other => panic!("expected an array, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn into_owned_matches_parse_kv2() {
+        let (_, borrowed) = parse_kv2_borrowed(INPUT).unwrap();
+        let owned: Vec<_> = borrowed.into_iter().map(KV2ObjectRef::into_owned).collect();
+        assert_eq!(owned, parse_kv2(INPUT).unwrap().1);
+    }
+}
+
+#[cfg(test)]
+mod span_tests {
+    use crate::{parse_document, parse_kv2_document_recovering, Diagnostic, Kv2Document};
+
+    const INPUT: &str = r#"<!-- dmx encoding keyvalues2 1 format dmx 22 -->
+"DmeModel"
+{
+    "id" "elementid" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+    "transform" "DmeTransform"
+    {
+        "position" "vector3" "0 0 0" <!-- origin -->
+    }
+    "children" "element_array"
+    [
+        "DmeDag" { "name" "string" "dag" },
+        "element" "1ec8a22e-5e14-45fe-9dab-02ffdd5772c8"
+    ]
+}
+"#;
+
+    #[test]
+    fn every_element_attribute_and_item_has_a_span() {
+        let doc = parse_document(INPUT).unwrap();
+        let text = |span: crate::Span| &INPUT[span.range()];
+        let model = doc.roots()[0];
+        let transform = doc[model].child("transform").unwrap();
+        let dag = doc[model].children("children")[0];
+
+        assert!(text(doc.span(model).unwrap()).starts_with("\"DmeModel\"\n{"));
+        assert_eq!(doc.span(model).unwrap().line, 2);
+        assert!(text(doc.span(transform).unwrap()).starts_with("\"DmeTransform\"\n    {"));
+        assert!(text(doc.attribute_span(model, "transform").unwrap()).ends_with("    }"));
+        assert_eq!(
+            text(doc.attribute_span(model, "id").unwrap()),
+            "\"id\" \"elementid\" \"90e0ae34-0671-478d-95f5-12fa5c905c7a\""
+        );
+
+        // The comment after the value isn't part of the attribute
+        let position = doc.attribute_span(transform, "position").unwrap();
+        assert_eq!(text(position), "\"position\" \"vector3\" \"0 0 0\"");
+        assert_eq!((position.line, position.column), (7, 9));
+
+        assert_eq!(
+            text(doc.span(dag).unwrap()),
+            "\"DmeDag\" { \"name\" \"string\" \"dag\" }"
+        );
+        assert_eq!(
+            text(doc.item_span(model, "children", 1).unwrap()),
+            "\"element\" \"1ec8a22e-5e14-45fe-9dab-02ffdd5772c8\""
+        );
+        assert_eq!(doc.item_span(model, "children", 2), None);
+
+        let mut built = Kv2Document::new(None, Vec::new());
+        let element = built.add_element("DmElement", "");
+        assert_eq!(built.span(element), None);
+
+        let (recovered, errors) = parse_kv2_document_recovering(INPUT);
+        assert!(errors.is_empty());
+        assert_eq!(recovered.span(recovered.roots()[0]), doc.span(model));
+    }
+
+    #[test]
+    fn validation_issues_point_at_the_source() {
+        let doc = parse_document(INPUT).unwrap();
+        let issues = doc.validate();
+        let dangling = issues
+            .iter()
+            .find(|issue| issue.location.index == Some(1))
+            .expect("expected the dangling reference");
+
+        let rendered = Diagnostic::from_issue(dangling, INPUT)
+            .unwrap()
+            .render(INPUT, "model.dmx");
+        assert!(rendered.contains(" --> model.dmx:12:9\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn roots_after_a_skipped_root_keep_their_spans() {
+        let input = r#""DmElement" { "name" "string" "first" }
+"Broken2" oops
+"DmElement" { "name" "string" "Third" }
+"#;
+        let (doc, errors) = parse_kv2_document_recovering(input);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert_eq!(doc.roots().len(), 2);
+
+        let third = doc.roots()[1];
+        assert_eq!(doc[third].name(), Some("Third"));
+        let span = doc.span(third).expect("expected a span for the third root");
+        assert_eq!(
+            &input[span.range()],
+            r#""DmElement" { "name" "string" "Third" }"#
+        );
+        assert_eq!(span.line, 3);
+        assert!(doc.attribute_span(third, "name").is_some());
+    }
+
+    #[test]
+    fn repeated_attributes_have_spans_by_occurrence() {
+        let input = r#""DmElement"
+{
+    "transform" "DmeTransform" { "name" "string" "first" }
+    "transform" "DmeTransform" { "name" "string" "second" }
+    "transform" "DmeTransform" { "name" "string" "third" }
+}
+"#;
+        let options = crate::ParseOptions {
+            duplicate_keys: crate::DuplicateKeyPolicy::KeepAll,
+            ..Default::default()
+        };
+        let doc = crate::parse_document_with(input, &options).unwrap();
+        let root = doc.roots()[0];
+        let text = |span: crate::Span| &input[span.range()];
+
+        assert!(text(doc.attribute_span(root, "transform").unwrap()).contains("first"));
+        assert!(text(doc.repeated_span(root, "transform", 1).unwrap()).contains("second"));
+        assert!(text(doc.repeated_span(root, "transform", 2).unwrap()).contains("third"));
+        assert_eq!(doc.repeated_span(root, "transform", 3), None);
+
+        // elements in repeated values point at their own occurrence
+        let crate::Attribute::Element(crate::ElementRef::Handle(third)) = &doc[root].repeated[1].1
+        else {
+            panic!("expected an element");
+        };
+        assert_eq!(doc[*third].name(), Some("third"));
+        assert_eq!(doc.span(*third).unwrap().line, 5);
+    }
+}
+
+#[cfg(test)]
+mod line_comment_tests {
+    use crate::{
+        from_reader_with, parse_document_with, parse_kv2, parse_kv2_with, Kv2Event, Kv2Events,
+        ParseOptions,
+    };
+
+    const INPUT: &str = r#"// exported by hand
+"DmElement" // the root
+{
+    "url" "string" "http://example.com" // not part of the "url"
+    "values" "int_array"
+    [
+        "1", // first
+        "2"
+    ]
+}
+"#;
+
+    const WITHOUT_COMMENTS: &str = r#""DmElement"
+{
+    "url" "string" "http://example.com"
+    "values" "int_array" [ "1", "2" ]
+}
+"#;
+
+    fn options() -> ParseOptions {
+        ParseOptions {
+            line_comments: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn line_comments_are_skipped_when_enabled() {
+        let (_, expected) = parse_kv2(WITHOUT_COMMENTS).unwrap();
+        let (rest, objects) = parse_kv2_with(INPUT, &options()).unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(objects, expected);
+        assert_eq!(
+            from_reader_with(INPUT.as_bytes(), &options()).unwrap(),
+            expected
+        );
+
+        // Without the option parsing stops at the first comment
+        assert!(parse_document_with(INPUT, &ParseOptions::default()).is_err());
+        assert!(from_reader_with(INPUT.as_bytes(), &ParseOptions::default()).is_err());
+
+        // The comment ends in a quote but isn't part of the span
+        let doc = parse_document_with(INPUT, &options()).unwrap();
+        let url = doc.attribute_span(doc.roots()[0], "url").unwrap();
+        assert_eq!(
+            &INPUT[url.range()],
+            "\"url\" \"string\" \"http://example.com\""
+        );
+    }
+
+    #[test]
+    fn comments_are_events() {
+        let events: Vec<Kv2Event> = Kv2Events::with_options(INPUT.as_bytes(), &options())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let comments: Vec<&str> = events
+            .iter()
+            .filter_map(|event| match event {
+                Kv2Event::Comment(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            comments,
+            [
+                " exported by hand",
+                " the root",
+                " not part of the \"url\"",
+                " first"
+            ]
+        );
+        assert_eq!(
+            events[0],
+            Kv2Event::Comment(" exported by hand".to_string())
+        );
+
+        let xml = "<!-- dmx encoding keyvalues2 1 format dmx 22 -->\n\"DmElement\" { }\n";
+        let first = Kv2Events::new(xml.as_bytes()).next().unwrap().unwrap();
+        assert_eq!(
+            first,
+            Kv2Event::Comment(" dmx encoding keyvalues2 1 format dmx 22 ".to_string())
+        );
+    }
+
+    #[test]
+    fn line_comments_can_come_before_the_header() {
+        let input = format!(
+            "// exported by tool\n<!-- dmx encoding keyvalues2 1 format dmx 22 -->\n{}",
+            WITHOUT_COMMENTS
+        );
+        let doc = parse_document_with(&input, &options()).unwrap();
+        let header = doc.header().expect("expected the header after the comment");
+        assert_eq!((header.format.as_str(), header.format_version), ("dmx", 22));
+        assert_eq!(doc.roots().len(), 1);
+    }
+}
+
+#[cfg(test)]
+mod duplicate_key_tests {
+    use crate::{
+        from_reader_with, parse_document_with, parse_kv2_document_recovering_with, parse_kv2_with,
+        write_kv2, Attribute, DuplicateKeyPolicy, Expected, KV2Value, ParseOptions, ReadError,
+        ValidationIssueKind, WriterOptions,
+    };
+
+    const INPUT: &str = r#""DmElement"
+{
+    "name" "string" "first"
+    "size" "int" "1"
+    "name" "string" "second"
+}
+"#;
+
+    fn options(duplicate_keys: DuplicateKeyPolicy) -> ParseOptions {
+        ParseOptions {
+            duplicate_keys,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn duplicate_keys_follow_the_policy() {
+        let first = KV2Value::String("first".to_string());
+        let second = KV2Value::String("second".to_string());
+        let cases = [
+            (DuplicateKeyPolicy::KeepFirst, first.clone(), vec![]),
+            (DuplicateKeyPolicy::KeepLast, second.clone(), vec![]),
+            (
+                DuplicateKeyPolicy::KeepAll,
+                first,
+                vec![("name".to_string(), second)],
+            ),
+        ];
+        for (policy, expected, repeated) in cases {
+            let (_, objects) = parse_kv2_with(INPUT, &options(policy)).unwrap();
+            assert_eq!(objects[0].fields["name"], expected, "{:?}", policy);
+            assert_eq!(objects[0].fields["size"], KV2Value::Int(1));
+            assert_eq!(objects[0].repeated, repeated, "{:?}", policy);
+
+            let read = from_reader_with(INPUT.as_bytes(), &options(policy)).unwrap();
+            assert_eq!(read, objects, "{:?}", policy);
+        }
+
+        // The kept value is the one the document points at
+        let doc = parse_document_with(INPUT, &options(DuplicateKeyPolicy::KeepFirst)).unwrap();
+        let name = doc.attribute_span(doc.roots()[0], "name").unwrap();
+        assert_eq!(&INPUT[name.range()], "\"name\" \"string\" \"first\"");
+        let is_duplicate =
+            |issue: &crate::ValidationIssue| issue.kind == ValidationIssueKind::DuplicateAttribute;
+        // Only documents keeping every value still have the duplicate
+        assert!(!doc.validate().iter().any(is_duplicate));
+        let doc = parse_document_with(INPUT, &options(DuplicateKeyPolicy::KeepAll)).unwrap();
+        assert!(doc.validate().iter().any(is_duplicate));
+    }
+
+    #[test]
+    fn duplicate_keys_can_be_errors() {
+        let error = parse_kv2_with(INPUT, &options(DuplicateKeyPolicy::Error)).unwrap_err();
+        assert_eq!((error.line, error.column), (5, 5));
+        assert_eq!(error.expected, [Expected::UniqueAttribute]);
+        assert_eq!(error.path.to_string(), "[0]/name");
+        assert_eq!(
+            error.to_string(),
+            "5:5: expected an attribute not already in the element in [0]/name"
+        );
+
+        match from_reader_with(INPUT.as_bytes(), &options(DuplicateKeyPolicy::Error)) {
+            Err(ReadError::Parse(read)) => assert_eq!(read, error),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+
+        let (doc, errors) =
+            parse_kv2_document_recovering_with(INPUT, &options(DuplicateKeyPolicy::Error));
+        assert_eq!(errors, [error]);
+        assert_eq!(
+            doc[doc.roots()[0]].value("name"),
+            Some(&KV2Value::String("first".to_string()))
+        );
+    }
+
+    #[test]
+    fn keep_all_keeps_repeated_arrays_apart() {
+        let input = r#""DmElement"
+{
+    "tags" "string_array" [ "a", "b" ]
+    "child" "DmElement" { "name" "string" "first" }
+    "tags" "string_array" [ "c" ]
+    "child" "DmElement" { "name" "string" "second" }
+}
+"#;
+        let tags = |items: &[&str]| {
+            KV2Value::Array(
+                items
+                    .iter()
+                    .map(|item| KV2Value::String(item.to_string()))
+                    .collect(),
+            )
+        };
+        let options = options(DuplicateKeyPolicy::KeepAll);
+        let (_, objects) = parse_kv2_with(input, &options).unwrap();
+        assert_eq!(objects[0].fields["tags"], tags(&["a", "b"]));
+        assert_eq!(objects[0].repeated.len(), 2);
+        assert_eq!(objects[0].repeated[0], ("tags".to_string(), tags(&["c"])));
+        assert_eq!(objects[0].repeated[1].0, "child");
+        assert_eq!(
+            from_reader_with(input.as_bytes(), &options).unwrap(),
+            objects
+        );
+
+        // Written back, every value is read again in the same place
+        let written = write_kv2(&objects, &WriterOptions::default());
+        assert!(written.contains("\"tags\" \"string_array\""), "{}", written);
+        let (_, reread) = parse_kv2_with(&written, &options).unwrap();
+        assert_eq!(reread, objects);
+
+        // Documents keep them too, with the nested elements
+        let mut doc = parse_document_with(input, &options).unwrap();
+        assert!(doc.collect_garbage().is_empty());
+        let root = &doc[doc.roots()[0]];
+        assert_eq!(doc.len(), 3);
+        assert_eq!(
+            root.repeated[0],
+            ("tags".to_string(), Attribute::Value(tags(&["c"])))
+        );
+        assert_eq!(doc.to_objects(), objects);
+    }
+}
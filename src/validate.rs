@@ -0,0 +1,253 @@
+//! Integrity checks for documents
+//!
+//! # Example
+//! ```rust
+//! use kv2::{parse_kv2_document, ValidationIssueKind};
+//!
+//! let (_, doc) = parse_kv2_document(r#"
+//! "DmElement"
+//! {
+//! "id" "elementid" "df939bf4-8dd6-435c-9eef-a6e25434ecca"
+//! "skeleton" "element" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+//! }
+//! "#).unwrap();
+//!
+//! let issues = doc.validate();
+//! assert_eq!(issues.len(), 1);
+//! assert!(matches!(issues[0].kind, ValidationIssueKind::DanglingReference(_)));
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::document::{ElementId, Kv2Document};
+use crate::element::{ElementHandle, ElementRef};
+
+/// Which classes the elements referenced by an attribute may have
+#[derive(Debug, Clone)]
+pub struct ValidationRules {
+    expected_classes: HashMap<(String, String), Vec<String>>,
+}
+
+impl ValidationRules {
+    /// Rules without any class expectations
+    pub fn new() -> ValidationRules {
+        ValidationRules {
+            expected_classes: HashMap::new(),
+        }
+    }
+
+    /// Expects elements referenced by `attribute` of `class` elements to be
+    /// one of `allowed`
+    pub fn expect_class(
+        mut self,
+        class: &str,
+        attribute: &str,
+        allowed: &[&str],
+    ) -> ValidationRules {
+        self.expected_classes.insert(
+            (class.to_string(), attribute.to_string()),
+            allowed.iter().map(|c| c.to_string()).collect(),
+        );
+        self
+    }
+
+    fn allowed(&self, class: &str, attribute: &str) -> Option<&[String]> {
+        self.expected_classes
+            .get(&(class.to_string(), attribute.to_string()))
+            .map(Vec::as_slice)
+    }
+}
+
+impl Default for ValidationRules {
+    /// Class expectations for common DMX models, presets and particle systems
+    fn default() -> ValidationRules {
+        let operators = ["DmeParticleOperator"];
+        ValidationRules::new()
+            .expect_class("DmeDag", "transform", &["DmeTransform"])
+            .expect_class("DmeModel", "transform", &["DmeTransform"])
+            .expect_class("DmeJoint", "transform", &["DmeTransform"])
+            .expect_class("DmeTransformsList", "transforms", &["DmeTransform"])
+            .expect_class("DmePresetGroup", "presets", &["DmePreset"])
+            .expect_class("DmeParticleSystemDefinition", "operators", &operators)
+            .expect_class("DmeParticleSystemDefinition", "renderers", &operators)
+            .expect_class("DmeParticleSystemDefinition", "initializers", &operators)
+            .expect_class("DmeParticleSystemDefinition", "emitters", &operators)
+            .expect_class("DmeParticleSystemDefinition", "forces", &operators)
+            .expect_class("DmeParticleSystemDefinition", "constraints", &operators)
+            .expect_class(
+                "DmeParticleSystemDefinition",
+                "children",
+                &["DmeParticleChild"],
+            )
+    }
+}
+
+/// Where a validation issue was found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    pub element: Option<ElementHandle>,
+    pub attribute: Option<String>,
+    /// Position in the `element_array`, if the issue is about an array item
+    pub index: Option<usize>,
+    /// Byte offset in the source, if the document was parsed from text
+    pub offset: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationIssueKind {
+    /// Another element has the same id, the location is the later element
+    DuplicateId(ElementId),
+    MissingId,
+    /// A reference to an id no element in the document has
+    DanglingReference(ElementId),
+    /// A reference to an element whose class the rules don't allow
+    WrongClass {
+        found: String,
+        expected: Vec<String>,
+    },
+    /// The attribute appears more than once in the element, the location is
+    /// a later occurrence in [`Element::repeated`](crate::Element::repeated)
+    DuplicateAttribute,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationIssue {
+    pub kind: ValidationIssueKind,
+    pub location: Location,
+}
+
+impl fmt::Display for ValidationIssueKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationIssueKind::DuplicateId(id) => write!(f, "duplicate element id {}", id),
+            ValidationIssueKind::MissingId => write!(f, "element has no id"),
+            ValidationIssueKind::DanglingReference(id) => {
+                write!(f, "reference to unknown element {}", id)
+            }
+            ValidationIssueKind::WrongClass { found, expected } => write!(
+                f,
+                "reference to a {} element, expected {}",
+                found,
+                expected.join(" or ")
+            ),
+            ValidationIssueKind::DuplicateAttribute => {
+                write!(f, "attribute appears more than once")
+            }
+        }
+    }
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(element) = self.location.element {
+            write!(f, " (element #{}", element.index())?;
+            if let Some(attribute) = &self.location.attribute {
+                write!(f, ", attribute {:?}", attribute)?;
+            }
+            if let Some(index) = self.location.index {
+                write!(f, ", item {}", index)?;
+            }
+            write!(f, ")")?;
+        }
+        if let Some(offset) = self.location.offset {
+            write!(f, " at byte {}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+impl Kv2Document {
+    /// Checks the document with the default [`ValidationRules`]
+    pub fn validate(&self) -> Vec<ValidationIssue> {
+        self.validate_with(&ValidationRules::default())
+    }
+
+    /// Reports duplicate and missing ids, dangling references, references
+    /// to elements of the wrong class and attributes that appear more than
+    /// once in an element
+    ///
+    /// Elements only keep repeated attributes when parsed with
+    /// [`DuplicateKeyPolicy::KeepAll`](crate::DuplicateKeyPolicy::KeepAll), the other policies drop them with a
+    /// warning while parsing. Class-less `"key" "value"` items of an
+    /// `element_array` aren't expected to have an id.
+    pub fn validate_with(&self, rules: &ValidationRules) -> Vec<ValidationIssue> {
+        let mut issues = Vec::new();
+        let mut seen: HashMap<&str, ElementHandle> = HashMap::new();
+
+        for (handle, element) in self.elements() {
+            let at_element = Location {
+                element: Some(handle),
+                offset: self.span(handle).map(|span| span.start),
+                ..Default::default()
+            };
+            if element.id().is_empty() {
+                // Class-less "key" "value" array items never have one
+                if !element.class_name.is_empty() {
+                    issues.push(ValidationIssue {
+                        kind: ValidationIssueKind::MissingId,
+                        location: at_element,
+                    });
+                }
+            } else if seen.insert(element.id(), handle).is_some() {
+                issues.push(ValidationIssue {
+                    kind: ValidationIssueKind::DuplicateId(element.id().to_string()),
+                    location: at_element,
+                });
+            }
+
+            let mut references: Vec<_> = element.references().collect();
+            references.sort_by_key(|(attribute, index, _)| (*attribute, *index));
+            for (attribute, index, target) in references {
+                let span = match index {
+                    Some(index) => self.item_span(handle, attribute, index),
+                    None => self.attribute_span(handle, attribute),
+                };
+                let location = Location {
+                    element: Some(handle),
+                    attribute: Some(attribute.to_string()),
+                    index,
+                    offset: span.map(|span| span.start),
+                };
+                let kind = match target {
+                    ElementRef::Null => continue,
+                    ElementRef::Dangling(id) => ValidationIssueKind::DanglingReference(id.clone()),
+                    ElementRef::Handle(target) => {
+                        let (Some(allowed), Some(target)) = (
+                            rules.allowed(&element.class_name, attribute),
+                            self.element(*target),
+                        ) else {
+                            continue;
+                        };
+                        if allowed.contains(&target.class_name) {
+                            continue;
+                        }
+                        ValidationIssueKind::WrongClass {
+                            found: target.class_name.clone(),
+                            expected: allowed.to_vec(),
+                        }
+                    }
+                };
+                issues.push(ValidationIssue { kind, location });
+            }
+
+            let mut occurrences: HashMap<&str, usize> = HashMap::new();
+            for (attribute, _) in &element.repeated {
+                let occurrence = occurrences.entry(attribute).or_insert(0);
+                *occurrence += 1;
+                let span = self.repeated_span(handle, attribute, *occurrence);
+                issues.push(ValidationIssue {
+                    kind: ValidationIssueKind::DuplicateAttribute,
+                    location: Location {
+                        element: Some(handle),
+                        attribute: Some(attribute.clone()),
+                        index: None,
+                        offset: span.map(|span| span.start),
+                    },
+                });
+            }
+        }
+
+        issues
+    }
+}
@@ -0,0 +1,473 @@
+//! Walking KV2 trees and documents without writing the recursion by hand
+//!
+//! # Example
+//! ```rust
+//! use kv2::{parse_kv2, visit_objects, Kv2Path, Kv2Visitor, KV2Value};
+//!
+//! struct Positions(Vec<String>);
+//!
+//! impl Kv2Visitor for Positions {
+//!     fn attribute(&mut self, path: &Kv2Path, name: &str, _value: &KV2Value) {
+//!         if name == "position" {
+//!             self.0.push(path.to_string());
+//!         }
+//!     }
+//! }
+//!
+//! let (_, objects) = parse_kv2(r#"
+//! "DmeModel"
+//! {
+//! "id" "elementid" "90e0ae34-0671-478d-95f5-12fa5c905c7a"
+//! "transform" "DmeTransform"
+//! {
+//!     "id" "elementid" "56f186a9-1316-46c1-b82d-f46d5f19e19e"
+//!     "position" "vector3" "0 0 0"
+//! }
+//! }
+//! "#).unwrap();
+//!
+//! let mut positions = Positions(Vec::new());
+//! visit_objects(&mut positions, &objects);
+//! assert_eq!(positions.0, vec!["[0]/transform/position"]);
+//! ```
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::document::Kv2Document;
+use crate::element::{Attribute, Element, ElementHandle};
+use crate::{KV2Object, KV2Value};
+
+/// One step of a [`Kv2Path`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PathSegment {
+    /// Position of the root element in the document
+    Root(usize),
+    Attribute(String),
+    /// A later value of an attribute that appears more than once in its
+    /// element, `1` for the second occurrence
+    Repeated(String, usize),
+    /// Position in the array named by the previous segment
+    Index(usize),
+}
+
+/// Where an element or attribute is, starting at a root element, displayed
+/// as `[0]/children[1]/transform`, or `[0]/transform#1` for a repeated
+/// attribute
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Kv2Path(Vec<PathSegment>);
+
+impl Kv2Path {
+    pub fn new() -> Kv2Path {
+        Kv2Path(Vec::new())
+    }
+
+    pub fn segments(&self) -> &[PathSegment] {
+        &self.0
+    }
+
+    pub fn push(&mut self, segment: PathSegment) {
+        self.0.push(segment);
+    }
+
+    pub fn pop(&mut self) -> Option<PathSegment> {
+        self.0.pop()
+    }
+
+    /// Returns a copy of the path with `segment` appended
+    pub fn join(&self, segment: PathSegment) -> Kv2Path {
+        let mut path = self.clone();
+        path.push(segment);
+        path
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Display for Kv2Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, segment) in self.0.iter().enumerate() {
+            match segment {
+                PathSegment::Root(index) => write!(f, "[{}]", index)?,
+                PathSegment::Attribute(name) => {
+                    if i > 0 {
+                        write!(f, "/")?;
+                    }
+                    write!(f, "{}", name)?;
+                }
+                PathSegment::Repeated(name, occurrence) => {
+                    if i > 0 {
+                        write!(f, "/")?;
+                    }
+                    write!(f, "{}#{}", name, occurrence)?;
+                }
+                PathSegment::Index(index) => write!(f, "[{}]", index)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Callbacks for [`visit_objects`], every method does nothing by default
+///
+/// Elements are entered before their attributes and left after them,
+/// attributes are visited in name order and the later values of repeated
+/// ones after them in source order. An attribute's path ends with its name,
+/// an array item's path with its index.
+pub trait Kv2Visitor {
+    fn enter_element(&mut self, _path: &Kv2Path, _object: &KV2Object) {}
+
+    fn leave_element(&mut self, _path: &Kv2Path, _object: &KV2Object) {}
+
+    fn attribute(&mut self, _path: &Kv2Path, _name: &str, _value: &KV2Value) {}
+
+    fn array_item(&mut self, _path: &Kv2Path, _index: usize, _value: &KV2Value) {}
+}
+
+/// Visits root objects and everything nested in them depth first
+pub fn visit_objects<V: Kv2Visitor + ?Sized>(visitor: &mut V, objects: &[KV2Object]) {
+    let mut path = Kv2Path::new();
+    for (i, object) in objects.iter().enumerate() {
+        path.push(PathSegment::Root(i));
+        visit_object(visitor, &mut path, object);
+        path.pop();
+    }
+}
+
+/// Visits one object and everything nested in it, `path` is where the
+/// object is
+pub fn visit_object<V: Kv2Visitor + ?Sized>(
+    visitor: &mut V,
+    path: &mut Kv2Path,
+    object: &KV2Object,
+) {
+    visitor.enter_element(path, object);
+
+    let mut fields: Vec<_> = object.fields.iter().collect();
+    fields.sort_by_key(|(name, _)| name.as_str());
+    for (name, value) in fields {
+        path.push(PathSegment::Attribute(name.clone()));
+        visit_value(visitor, path, name, value);
+        path.pop();
+    }
+    for (segment, (name, value)) in repeated_segments(&object.repeated).zip(&object.repeated) {
+        path.push(segment);
+        visit_value(visitor, path, name, value);
+        path.pop();
+    }
+
+    visitor.leave_element(path, object);
+}
+
+fn visit_value<V: Kv2Visitor + ?Sized>(
+    visitor: &mut V,
+    path: &mut Kv2Path,
+    name: &str,
+    value: &KV2Value,
+) {
+    visitor.attribute(path, name, value);
+    match value {
+        KV2Value::Object(child) => visit_object(visitor, path, child),
+        KV2Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                path.push(PathSegment::Index(i));
+                visitor.array_item(path, i, item);
+                if let KV2Value::Object(child) = item {
+                    visit_object(visitor, path, child);
+                }
+                path.pop();
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The path segments of the later values of repeated attributes, in order
+pub(crate) fn repeated_segments<T>(
+    repeated: &[(String, T)],
+) -> impl Iterator<Item = PathSegment> + '_ {
+    let mut occurrences: HashMap<&str, usize> = HashMap::new();
+    repeated.iter().map(move |(name, _)| {
+        let occurrence = occurrences.entry(name).or_insert(0);
+        *occurrence += 1;
+        PathSegment::Repeated(name.clone(), *occurrence)
+    })
+}
+
+/// An attribute offered to [`Kv2VisitorMut::keep_attribute`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VisitedAttribute<'a> {
+    /// An attribute of an object in a tree, nested objects included
+    Tree(&'a KV2Value),
+    /// An attribute of a document element
+    Document(&'a Attribute),
+}
+
+impl<'a> VisitedAttribute<'a> {
+    /// The value of the attribute, `None` for document attributes holding
+    /// element references
+    pub fn value(&self) -> Option<&'a KV2Value> {
+        match *self {
+            VisitedAttribute::Tree(value) => Some(value),
+            VisitedAttribute::Document(Attribute::Value(value)) => Some(value),
+            VisitedAttribute::Document(_) => None,
+        }
+    }
+}
+
+/// Callbacks for in-place bulk edits with [`visit_objects_mut`] or
+/// [`Kv2Document::visit_mut`], every method does nothing by default
+///
+/// For each element [`element`](Self::element) is called first, then every
+/// attribute in name order, followed by the later values of repeated ones in
+/// source order, goes through [`keep_attribute`](Self::keep_attribute) and,
+/// if kept and not holding elements, [`value`](Self::value).
+pub trait Kv2VisitorMut {
+    /// Called with the path of the element, can rename its class
+    fn element(&mut self, _path: &Kv2Path, _class_name: &mut String) {}
+
+    /// Returning `false` removes the attribute from the element
+    fn keep_attribute(
+        &mut self,
+        _path: &Kv2Path,
+        _name: &str,
+        _attribute: VisitedAttribute<'_>,
+    ) -> bool {
+        true
+    }
+
+    /// Called for every attribute that doesn't hold elements, including
+    /// arrays of values
+    fn value(&mut self, _path: &Kv2Path, _name: &str, _value: &mut KV2Value) {}
+}
+
+/// Edits root objects and everything nested in them depth first
+pub fn visit_objects_mut<V: Kv2VisitorMut + ?Sized>(visitor: &mut V, objects: &mut [KV2Object]) {
+    let mut path = Kv2Path::new();
+    for (i, object) in objects.iter_mut().enumerate() {
+        path.push(PathSegment::Root(i));
+        visit_object_mut(visitor, &mut path, object);
+        path.pop();
+    }
+}
+
+/// Edits one object and everything nested in it, `path` is where the object
+/// is
+pub fn visit_object_mut<V: Kv2VisitorMut + ?Sized>(
+    visitor: &mut V,
+    path: &mut Kv2Path,
+    object: &mut KV2Object,
+) {
+    visitor.element(path, &mut object.class_name);
+
+    let mut names: Vec<String> = object.fields.keys().cloned().collect();
+    names.sort();
+    for name in names {
+        path.push(PathSegment::Attribute(name.clone()));
+        let keep = match object.fields.get(&name) {
+            Some(value) => visitor.keep_attribute(path, &name, VisitedAttribute::Tree(value)),
+            None => true,
+        };
+        if !keep {
+            object.fields.remove(&name);
+            path.pop();
+            continue;
+        }
+
+        if let Some(value) = object.fields.get_mut(&name) {
+            edit_value(visitor, path, &name, value);
+        }
+        path.pop();
+    }
+
+    let mut segments = repeated_segments(&object.repeated)
+        .collect::<Vec<_>>()
+        .into_iter();
+    object.repeated.retain_mut(|(name, value)| {
+        let Some(segment) = segments.next() else {
+            return true;
+        };
+        path.push(segment);
+        let keep = visitor.keep_attribute(path, name, VisitedAttribute::Tree(value));
+        if keep {
+            edit_value(visitor, path, name, value);
+        }
+        path.pop();
+        keep
+    });
+}
+
+fn edit_value<V: Kv2VisitorMut + ?Sized>(
+    visitor: &mut V,
+    path: &mut Kv2Path,
+    name: &str,
+    value: &mut KV2Value,
+) {
+    match value {
+        KV2Value::Object(child) => visit_object_mut(visitor, path, child),
+        KV2Value::Array(items) if items.iter().any(holds_elements) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                if let KV2Value::Object(child) = item {
+                    path.push(PathSegment::Index(i));
+                    visit_object_mut(visitor, path, child);
+                    path.pop();
+                }
+            }
+        }
+        KV2Value::Element(_) => {}
+        value => visitor.value(path, name, value),
+    }
+}
+
+fn holds_elements(value: &KV2Value) -> bool {
+    matches!(value, KV2Value::Object(_) | KV2Value::Element(_))
+}
+
+/// An element reached by [`Kv2Document::walk`]
+#[derive(Debug, Clone)]
+pub struct WalkEntry<'a> {
+    pub handle: ElementHandle,
+    pub element: &'a Element,
+    /// The path the element was first reached by
+    pub path: Kv2Path,
+    /// Number of references followed from the root, 0 for roots
+    pub depth: usize,
+}
+
+/// Depth-first iterator over the elements reachable from a document's roots
+pub struct Walk<'a> {
+    document: &'a Kv2Document,
+    pending: Vec<(ElementHandle, Kv2Path, usize)>,
+    visited: HashSet<ElementHandle>,
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = WalkEntry<'a>;
+
+    fn next(&mut self) -> Option<WalkEntry<'a>> {
+        while let Some((handle, path, depth)) = self.pending.pop() {
+            if !self.visited.insert(handle) {
+                continue;
+            }
+            let Some(element) = self.document.element(handle) else {
+                continue;
+            };
+
+            // Push in reverse so children come out in attribute name order,
+            // then the later values of repeated attributes in source order
+            let mut names: Vec<&String> = element.attributes.keys().collect();
+            names.sort();
+            let attributes = names.into_iter().map(|name| {
+                (
+                    PathSegment::Attribute(name.clone()),
+                    &element.attributes[name],
+                )
+            });
+            let repeated = repeated_segments(&element.repeated)
+                .zip(element.repeated.iter().map(|(_, attribute)| attribute));
+            let attributes: Vec<_> = attributes.chain(repeated).collect();
+            for (segment, attribute) in attributes.into_iter().rev() {
+                let attribute_path = path.join(segment);
+                match attribute {
+                    Attribute::Element(target) => {
+                        if let Some(target) = target.handle() {
+                            self.pending.push((target, attribute_path, depth + 1));
+                        }
+                    }
+                    Attribute::ElementArray(targets) => {
+                        for (i, target) in targets.iter().enumerate().rev() {
+                            if let Some(target) = target.handle() {
+                                let item_path = attribute_path.join(PathSegment::Index(i));
+                                self.pending.push((target, item_path, depth + 1));
+                            }
+                        }
+                    }
+                    Attribute::Value(_) => {}
+                }
+            }
+
+            return Some(WalkEntry {
+                handle,
+                element,
+                path,
+                depth,
+            });
+        }
+        None
+    }
+}
+
+impl Kv2Document {
+    /// Edits every element reachable from the roots once, in the order of
+    /// [`walk`](Self::walk)
+    pub fn visit_mut<V: Kv2VisitorMut + ?Sized>(&mut self, visitor: &mut V) {
+        let entries: Vec<(ElementHandle, Kv2Path)> = self
+            .walk()
+            .map(|entry| (entry.handle, entry.path))
+            .collect();
+
+        for (handle, mut path) in entries {
+            let element = &mut self[handle];
+            visitor.element(&path, &mut element.class_name);
+
+            let mut names: Vec<String> = element.attributes.keys().cloned().collect();
+            names.sort();
+            for name in names {
+                path.push(PathSegment::Attribute(name.clone()));
+                let keep = match element.attributes.get(&name) {
+                    Some(attribute) => {
+                        visitor.keep_attribute(&path, &name, VisitedAttribute::Document(attribute))
+                    }
+                    None => true,
+                };
+                if !keep {
+                    element.attributes.remove(&name);
+                } else if let Some(Attribute::Value(value)) = element.attributes.get_mut(&name) {
+                    visitor.value(&path, &name, value);
+                }
+                path.pop();
+            }
+
+            let mut segments = repeated_segments(&element.repeated)
+                .collect::<Vec<_>>()
+                .into_iter();
+            element.repeated.retain_mut(|(name, attribute)| {
+                let Some(segment) = segments.next() else {
+                    return true;
+                };
+                path.push(segment);
+                let keep =
+                    visitor.keep_attribute(&path, name, VisitedAttribute::Document(attribute));
+                if keep {
+                    if let Attribute::Value(value) = attribute {
+                        visitor.value(&path, name, value);
+                    }
+                }
+                path.pop();
+                keep
+            });
+        }
+    }
+
+    /// Walks the elements reachable from the roots depth first, visiting
+    /// each element once even if it is referenced from several places
+    pub fn walk(&self) -> Walk<'_> {
+        let pending = self
+            .roots()
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, root)| (*root, Kv2Path(vec![PathSegment::Root(i)]), 0))
+            .collect();
+        Walk {
+            document: self,
+            pending,
+            visited: HashSet::new(),
+        }
+    }
+}
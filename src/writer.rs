@@ -0,0 +1,247 @@
+//! Writing [`KV2Object`]s back out as KV2 text
+//!
+//! # Example
+//! ```rust
+//! use kv2::{parse_kv2, write_kv2, WriterOptions};
+//!
+//! let input = r#"
+//! "DmElement"
+//! {
+//! "id" "elementid" "df939bf4-8dd6-435c-9eef-a6e25434ecca"
+//! "name" "string" "root"
+//! }
+//! "#;
+//!
+//! let (_, objects) = parse_kv2(input).unwrap();
+//! let output = write_kv2(&objects, &WriterOptions::default());
+//! assert!(output.contains("\"name\" \"string\" \"root\""));
+//! ```
+use crate::{KV2Object, KV2Value};
+
+/// How infinities and NaNs are spelled when writing floats
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FloatSpelling {
+    /// `inf`, `-inf` and `nan`, as printed by the C runtime on most platforms
+    #[default]
+    Portable,
+    /// `1.#INF`, `-1.#INF`, `1.#QNAN` and `-1.#IND`, as printed by MSVC
+    Msvc,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WriterOptions {
+    pub float_spelling: FloatSpelling,
+}
+
+/// Writes the given root objects as a KV2 document
+///
+/// The `id` attribute is written first with the `elementid` type, the other
+/// attributes follow sorted by name so the output is stable. Later values of
+/// repeated attributes come last, in their order.
+///
+/// Values don't remember the type they were declared with, so some types
+/// change on the way out:
+/// - vectors are written as `vector2`, `vector3` or `vector4` by their
+///   length, so `qangle` and `color` attributes come out as `vector3` and
+///   `vector4`
+/// - empty arrays are written as `element_array`
+/// - only the `id` attribute is written as `elementid`, other `elementid`
+///   attributes come out as `string`
+pub fn write_kv2(objects: &[KV2Object], options: &WriterOptions) -> String {
+    let mut writer = Kv2Writer {
+        out: String::new(),
+        options,
+    };
+
+    for object in objects {
+        writer.write_object(object, 0);
+        writer.out.push('\n');
+    }
+
+    writer.out
+}
+
+struct Kv2Writer<'a> {
+    out: String,
+    options: &'a WriterOptions,
+}
+
+impl Kv2Writer<'_> {
+    fn write_object(&mut self, object: &KV2Object, depth: usize) {
+        self.write_quoted(&object.class_name);
+        self.out.push('\n');
+        self.write_indent(depth);
+        self.out.push_str("{\n");
+
+        let repeated = object.repeated.iter().map(|(key, value)| (key, value));
+        for (key, value) in sorted_fields(object).into_iter().chain(repeated) {
+            self.write_indent(depth + 1);
+            self.write_attribute(key, value, depth + 1);
+        }
+
+        self.write_indent(depth);
+        self.out.push('}');
+    }
+
+    fn write_attribute(&mut self, key: &str, value: &KV2Value, depth: usize) {
+        self.write_quoted(key);
+        self.out.push(' ');
+
+        match value {
+            KV2Value::Object(object) => {
+                self.write_object(object, depth);
+                self.out.push('\n');
+            }
+            KV2Value::Array(values) => {
+                self.write_quoted(&format!("{}_array", array_type_name(values)));
+                self.out.push('\n');
+                self.write_array(values, depth);
+                self.out.push('\n');
+            }
+            KV2Value::String(_) if key == "id" => {
+                self.write_quoted("elementid");
+                self.out.push(' ');
+                self.write_scalar(value);
+                self.out.push('\n');
+            }
+            _ => {
+                self.write_quoted(type_name(value));
+                self.out.push(' ');
+                self.write_scalar(value);
+                self.out.push('\n');
+            }
+        }
+    }
+
+    fn write_array(&mut self, values: &[KV2Value], depth: usize) {
+        self.write_indent(depth);
+        self.out.push_str("[\n");
+
+        for (i, value) in values.iter().enumerate() {
+            self.write_indent(depth + 1);
+            match value {
+                KV2Value::Element(_) => {
+                    self.write_quoted("element");
+                    self.out.push(' ');
+                    self.write_scalar(value);
+                }
+                KV2Value::Object(object) if object.class_name.is_empty() => {
+                    // Other key-value pairs in arrays are parsed as class-less objects
+                    if let Some((key, value)) = object.fields.iter().next() {
+                        self.write_quoted(key);
+                        self.out.push(' ');
+                        self.write_scalar(value);
+                    }
+                }
+                KV2Value::Object(object) => self.write_object(object, depth + 1),
+                _ => self.write_scalar(value),
+            }
+            if i + 1 < values.len() {
+                self.out.push(',');
+            }
+            self.out.push('\n');
+        }
+
+        self.write_indent(depth);
+        self.out.push(']');
+    }
+
+    fn write_scalar(&mut self, value: &KV2Value) {
+        let text = match value {
+            KV2Value::Bool(b) => (if *b { "1" } else { "0" }).to_string(),
+            KV2Value::Int(i) => i.to_string(),
+            KV2Value::Double(d) => format_float(*d, self.options.float_spelling),
+            KV2Value::Vector(v) | KV2Value::Quaternion(v) => v
+                .iter()
+                .map(|d| format_float(*d, self.options.float_spelling))
+                .collect::<Vec<_>>()
+                .join(" "),
+            KV2Value::String(s) | KV2Value::Element(s) => s.clone(),
+            KV2Value::Array(_) | KV2Value::Object(_) => String::new(),
+        };
+        self.write_quoted(&text);
+    }
+
+    /// Writes `s` in double quotes, escaping quotes, backslashes, newlines
+    /// and tabs
+    fn write_quoted(&mut self, s: &str) {
+        self.out.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => self.out.push_str("\\\""),
+                '\\' => self.out.push_str("\\\\"),
+                '\n' => self.out.push_str("\\n"),
+                '\t' => self.out.push_str("\\t"),
+                c => self.out.push(c),
+            }
+        }
+        self.out.push('"');
+    }
+
+    fn write_indent(&mut self, depth: usize) {
+        for _ in 0..depth {
+            self.out.push('\t');
+        }
+    }
+}
+
+fn sorted_fields(object: &KV2Object) -> Vec<(&String, &KV2Value)> {
+    let mut fields: Vec<_> = object.fields.iter().collect();
+    fields.sort_by_key(|(key, _)| (key.as_str() != "id", key.as_str()));
+    fields
+}
+
+fn type_name(value: &KV2Value) -> &'static str {
+    match value {
+        KV2Value::Bool(_) => "bool",
+        KV2Value::Int(_) => "int",
+        KV2Value::Double(_) => "float",
+        KV2Value::Vector(v) => match v.len() {
+            2 => "vector2",
+            4 => "vector4",
+            _ => "vector3",
+        },
+        KV2Value::Quaternion(_) => "quaternion",
+        KV2Value::String(_) => "string",
+        KV2Value::Element(_) | KV2Value::Array(_) | KV2Value::Object(_) => "element",
+    }
+}
+
+fn array_type_name(values: &[KV2Value]) -> &'static str {
+    // Empty arrays carry no type information, element arrays are by far the
+    // most common kind in DMX files
+    values.first().map(type_name).unwrap_or("element")
+}
+
+/// Formats a float the way KV2 expects it, spelling infinities and NaNs
+/// according to `spelling`
+pub fn format_float(value: f64, spelling: FloatSpelling) -> String {
+    if value.is_finite() {
+        return value.to_string();
+    }
+
+    match spelling {
+        FloatSpelling::Portable => {
+            if value.is_nan() {
+                "nan".to_string()
+            } else if value.is_sign_negative() {
+                "-inf".to_string()
+            } else {
+                "inf".to_string()
+            }
+        }
+        FloatSpelling::Msvc => {
+            // MSVC prints the default quiet NaN produced by invalid operations
+            // (which has the sign bit set) as "-1.#IND"
+            if value.is_nan() && value.is_sign_negative() {
+                "-1.#IND".to_string()
+            } else if value.is_nan() {
+                "1.#QNAN".to_string()
+            } else if value.is_sign_negative() {
+                "-1.#INF".to_string()
+            } else {
+                "1.#INF".to_string()
+            }
+        }
+    }
+}